@@ -1,5 +1,6 @@
-use std::{cmp::min, num::NonZeroU32};
+use std::{cmp::min, num::NonZeroU32, time::Duration};
 
+use axum::extract::ws::Message;
 use serde::{Deserialize, Serialize};
 use serde_with::{serde_as, DisplayFromStr, FromInto, TryFromInto};
 use shakmaty::{
@@ -10,18 +11,60 @@ use shakmaty::{
 };
 use thiserror::Error;
 
-use crate::model::{ClientSecret, Engine, JobId, MultiPv, ProviderSecret, SessionId, UciVariant};
+use crate::{
+    emit::Emit,
+    limits::Limits,
+    model::{
+        ClientSecret, Engine, InstanceId, JobId, MultiPv, ProviderSecret, SessionId, UciVariant,
+    },
+    uci::{Go, UciIn},
+};
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 #[serde(rename_all = "camelCase")]
 pub enum Search {
     Movetime(u32),
     Depth(u32),
-    Nodes(u64)
+    Nodes(u64),
+    /// Search until the client is gone, but at most for
+    /// `Limits::max_infinite`.
+    Infinite(True),
+}
+
+/// Flag that can only be `true`, to select a variant without parameters.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[serde(try_from = "bool", into = "bool")]
+pub struct True;
+
+impl TryFrom<bool> for True {
+    type Error = &'static str;
+
+    fn try_from(flag: bool) -> Result<True, &'static str> {
+        if flag {
+            Ok(True)
+        } else {
+            Err("expected true")
+        }
+    }
+}
+
+impl From<True> for bool {
+    fn from(True: True) -> bool {
+        true
+    }
+}
+
+/// Interactive analysis goes ahead of batch analysis on the same provider.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "camelCase")]
+pub enum Priority {
+    Batch,
+    #[default]
+    Interactive,
 }
 
 #[serde_as]
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 #[serde(rename_all = "camelCase")]
 pub struct Work {
     session_id: SessionId,
@@ -37,6 +80,19 @@ pub struct Work {
     initial_fen: Fen,
     #[serde_as(as = "Vec<DisplayFromStr>")]
     moves: Vec<Uci>,
+    /// Only consider these moves from the final position, if any.
+    #[serde_as(as = "Vec<DisplayFromStr>")]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    searchmoves: Vec<Uci>,
+    #[serde(default)]
+    priority: Priority,
+    /// Also emit seldepth, nps, hashfull, tbhits, sbhits, cpuload and the
+    /// move currently being searched.
+    #[serde(default)]
+    extended_info: bool,
+    /// Also emit `info string` lines of the engine.
+    #[serde(default)]
+    info_strings: bool,
 }
 
 #[derive(Error, Debug)]
@@ -47,12 +103,88 @@ pub enum InvalidWorkError {
     IllegalUci(#[from] IllegalUciError),
     #[error("too many moves")]
     TooManyMoves,
+    #[error("multiPv must be at most {0}")]
+    TooManyPvs(MultiPv),
     #[error("unsupported variant")]
     UnsupportedVariant,
 }
 
 impl Work {
-    pub fn sanitize(self, engine: &Engine) -> Result<(Work, VariantPosition), InvalidWorkError> {
+    pub fn session_id(&self) -> &SessionId {
+        &self.session_id
+    }
+
+    pub fn priority(&self) -> Priority {
+        self.priority
+    }
+
+    pub fn multi_pv(&self) -> MultiPv {
+        self.multi_pv
+    }
+
+    pub fn is_infinite(&self) -> bool {
+        matches!(self.search, Search::Infinite(True))
+    }
+
+    pub fn extended_info(&self) -> bool {
+        self.extended_info
+    }
+
+    pub fn info_strings(&self) -> bool {
+        self.info_strings
+    }
+
+    /// Commands that set up an engine and start the search for this work.
+    pub fn to_uci_commands(&self) -> Vec<UciIn> {
+        // Engine processes are reused across work, so every option is set,
+        // even if it is the engine default.
+        vec![
+            UciIn::setoption("UCI_Chess960", true),
+            UciIn::setoption("UCI_Variant", self.variant.uci()),
+            UciIn::setoption("Threads", self.threads),
+            UciIn::setoption("Hash", self.hash),
+            UciIn::setoption("MultiPV", self.multi_pv),
+            UciIn::setoption("UCI_AnalyseMode", true),
+            UciIn::Ucinewgame,
+            UciIn::Isready,
+            UciIn::Position {
+                fen: Some(self.initial_fen.clone()),
+                moves: self.moves.clone(),
+            },
+            UciIn::Go(Go {
+                searchmoves: (!self.searchmoves.is_empty()).then(|| self.searchmoves.clone()),
+                ..match self.search {
+                    Search::Movetime(movetime) => Go {
+                        movetime: Some(Duration::from_millis(u64::from(movetime))),
+                        ..Go::default()
+                    },
+                    Search::Depth(depth) => Go {
+                        depth: Some(depth),
+                        ..Go::default()
+                    },
+                    Search::Nodes(nodes) => Go {
+                        nodes: Some(nodes),
+                        ..Go::default()
+                    },
+                    Search::Infinite(True) => Go {
+                        infinite: true,
+                        ..Go::default()
+                    },
+                }
+            }),
+        ]
+    }
+
+    pub fn fits(&self, capacity: &Capacity) -> bool {
+        self.threads <= capacity.max_threads && self.hash <= capacity.max_hash
+    }
+
+    #[allow(clippy::result_large_err)]
+    pub fn sanitize(
+        self,
+        engine: &Engine,
+        limits: &Limits,
+    ) -> Result<(Work, VariantPosition), InvalidWorkError> {
         if !engine
             .config
             .variants
@@ -63,6 +195,10 @@ impl Work {
             return Err(InvalidWorkError::UnsupportedVariant);
         }
 
+        if self.multi_pv > limits.max_multi_pv {
+            return Err(InvalidWorkError::TooManyPvs(limits.max_multi_pv));
+        }
+
         let mut pos = VariantPosition::from_setup(
             self.variant,
             self.initial_fen.into_setup(),
@@ -70,7 +206,7 @@ impl Work {
         )?;
         let initial_fen = Fen(pos.clone().into_setup(EnPassantMode::Legal));
 
-        if self.moves.len() > 600 {
+        if self.moves.len() > limits.max_moves {
             return Err(InvalidWorkError::TooManyMoves);
         }
         let mut moves = Vec::with_capacity(self.moves.len());
@@ -79,6 +215,14 @@ impl Work {
             moves.push(m.to_uci(CastlingMode::Chess960));
             pos.play_unchecked(&m);
         }
+        let mut searchmoves = Vec::with_capacity(self.searchmoves.len());
+        for uci in self.searchmoves {
+            // Different notations of the same castling move are duplicates.
+            let uci = uci.to_move(&pos)?.to_uci(CastlingMode::Chess960);
+            if !searchmoves.contains(&uci) {
+                searchmoves.push(uci);
+            }
+        }
 
         Ok((
             Work {
@@ -90,6 +234,10 @@ impl Work {
                 variant: self.variant,
                 initial_fen,
                 moves,
+                searchmoves,
+                priority: self.priority,
+                extended_info: self.extended_info,
+                info_strings: self.info_strings,
             },
             pos,
         ))
@@ -105,14 +253,114 @@ pub struct AnalyseRequest {
 
 #[derive(Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
+pub struct CancelRequest {
+    pub client_secret: ClientSecret,
+    pub session_id: SessionId,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
 pub struct AcquireRequest {
     pub provider_secret: ProviderSecret,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub instance: Option<Instance>,
 }
 
-#[derive(Serialize, Debug)]
+/// Identifies one of possibly several providers serving the same engines.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Instance {
+    pub id: InstanceId,
+    #[serde(flatten)]
+    pub capacity: Capacity,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Capacity {
+    pub max_threads: NonZeroU32,
+    pub max_hash: NonZeroU32,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct AcquireResponse {
     pub id: JobId,
     pub work: Work,
     pub engine: Engine,
 }
+
+/// First message of a provider on its socket.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SocketHello {
+    pub provider_secret: ProviderSecret,
+    #[serde(default)]
+    pub instance: Option<Instance>,
+    /// Number of jobs the provider is willing to work on at the same time.
+    #[serde(default = "default_concurrency")]
+    pub concurrency: NonZeroU32,
+}
+
+fn default_concurrency() -> NonZeroU32 {
+    NonZeroU32::new(1).unwrap()
+}
+
+/// A line of UCI output for a job, sent by a provider on its socket.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SocketLine {
+    pub id: JobId,
+    pub line: String,
+}
+
+/// Pushed to providers on their socket.
+#[derive(Serialize, Debug)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum SocketMessage {
+    /// New work, exactly like the response to an acquire request.
+    Work(Box<AcquireResponse>),
+    /// The job is over. Stop searching, if still running.
+    Stop { id: JobId },
+}
+
+impl SocketMessage {
+    pub fn to_message(&self) -> Message {
+        Message::Text(serde_json::to_string(self).expect("serialize socket message"))
+    }
+}
+
+/// First message of a client on its socket.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ClientHello {
+    pub client_secret: ClientSecret,
+}
+
+/// New work from a client, replacing the previous work on the same socket.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ClientWork {
+    pub work: Work,
+}
+
+/// Pushed to clients on their socket.
+#[derive(Serialize, Debug)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum ClientMessage {
+    Emit {
+        id: JobId,
+        #[serde(flatten)]
+        emit: Emit,
+    },
+    /// The analysis is complete.
+    Done { id: JobId },
+    /// The latest work could not be analysed.
+    Error { error: String },
+}
+
+impl ClientMessage {
+    pub fn to_message(&self) -> Message {
+        Message::Text(serde_json::to_string(self).expect("serialize client message"))
+    }
+}
@@ -0,0 +1,86 @@
+use std::{ffi::OsString, num::NonZeroU32, path::PathBuf, time::Duration};
+
+use clap::{builder::PathBufValueParser, Parser};
+use lila_engine::{
+    api::{Capacity, Instance},
+    model::{InstanceId, ProviderSecret},
+    provider::{self, ProviderConfig},
+    uci::Parsing,
+};
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+
+/// Serves work from the broker with a local UCI engine.
+#[derive(Parser)]
+struct Opt {
+    /// Base URL of the broker.
+    #[arg(
+        long,
+        env = "LILA_ENGINE_ENDPOINT",
+        default_value = "https://engine.lichess.ovh"
+    )]
+    pub endpoint: String,
+    /// Secret shared with the engines registered for this provider.
+    #[arg(long, env = "LILA_ENGINE_PROVIDER_SECRET")]
+    pub provider_secret: String,
+    /// Identifies this instance, if several providers serve the same engines.
+    #[arg(long, env = "LILA_ENGINE_INSTANCE", requires = "max_threads")]
+    pub instance: Option<String>,
+    /// Most threads this instance can give to a search.
+    #[arg(long, env = "LILA_ENGINE_MAX_THREADS", requires = "max_hash")]
+    pub max_threads: Option<NonZeroU32>,
+    /// Most hash (MiB) this instance can give to a search.
+    #[arg(long, env = "LILA_ENGINE_MAX_HASH")]
+    pub max_hash: Option<NonZeroU32>,
+    /// Seconds to wait before retrying when the broker cannot be reached.
+    #[arg(long, env = "LILA_ENGINE_RETRY_AFTER", default_value_t = 5)]
+    pub retry_after: u64,
+    /// How to treat unknown tokens in info lines of the engine. Should match
+    /// the broker, because lines that do not parse are not sent.
+    #[arg(
+        long,
+        value_enum,
+        env = "LILA_ENGINE_UCI_PARSING",
+        default_value_t = Parsing::Strict
+    )]
+    pub uci_parsing: Parsing,
+    /// UCI engine executable.
+    #[arg(value_parser = PathBufValueParser::new())]
+    pub engine: PathBuf,
+    /// Arguments for the engine.
+    pub engine_args: Vec<OsString>,
+}
+
+#[tokio::main]
+async fn main() {
+    tracing_subscriber::registry()
+        .with(tracing_subscriber::EnvFilter::new(
+            std::env::var("LILA_ENGINE_LOG").unwrap_or_else(|_| "lila_engine=info".into()),
+        ))
+        .with(tracing_subscriber::fmt::layer().without_time())
+        .init();
+
+    let opt = Opt::parse();
+
+    let instance = opt.instance.map(|id| Instance {
+        id: InstanceId::from(id),
+        capacity: Capacity {
+            max_threads: opt.max_threads.expect("max threads"),
+            max_hash: opt.max_hash.expect("max hash"),
+        },
+    });
+
+    let config = ProviderConfig {
+        endpoint: opt.endpoint.parse().expect("endpoint"),
+        provider_secret: ProviderSecret::from(opt.provider_secret),
+        instance,
+        engine: opt.engine,
+        engine_args: opt.engine_args,
+        retry_after: Duration::from_secs(opt.retry_after),
+        uci_parsing: opt.uci_parsing,
+    };
+
+    if let Err(err) = provider::run(config).await {
+        log::error!("{err}");
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,52 @@
+use std::{num::NonZeroUsize, path::PathBuf, time::Duration};
+
+use clap::{builder::PathBufValueParser, Parser};
+use lila_engine::simulator::{self, SimulatorConfig};
+use tokio::io::{stdin, stdout, BufReader};
+
+/// Simulated UCI engine for testing providers and the broker.
+#[derive(Parser)]
+struct Opt {
+    /// Name reported to the GUI.
+    #[arg(long, default_value_t = SimulatorConfig::default().name)]
+    pub name: String,
+    /// Seed for random searches.
+    #[arg(long, default_value_t = SimulatorConfig::default().seed)]
+    pub seed: u64,
+    /// Depth of random searches.
+    #[arg(long, default_value_t = SimulatorConfig::default().max_depth)]
+    pub depth: u32,
+    /// Milliseconds between two lines of search output.
+    #[arg(long, default_value_t = SimulatorConfig::default().interval.as_millis() as u64)]
+    pub interval: u64,
+    /// Play back the lines of this file for each search.
+    #[arg(long, value_parser = PathBufValueParser::new())]
+    pub script: Option<PathBuf>,
+    /// Inject a malformed line before every nth line of search output.
+    #[arg(long)]
+    pub malformed_every: Option<NonZeroUsize>,
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    let opt = Opt::parse();
+
+    let config = SimulatorConfig {
+        name: opt.name,
+        seed: opt.seed,
+        max_depth: opt.depth,
+        interval: Duration::from_millis(opt.interval),
+        script: opt.script.map(|path| {
+            std::fs::read_to_string(path)
+                .expect("script")
+                .lines()
+                .map(ToOwned::to_owned)
+                .collect()
+        }),
+        malformed_every: opt.malformed_every,
+    };
+
+    simulator::run(config, BufReader::new(stdin()), stdout())
+        .await
+        .expect("io");
+}
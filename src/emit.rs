@@ -5,8 +5,9 @@ use serde_with::{serde_as, DisplayFromStr, DurationMilliSeconds};
 use shakmaty::{uci::Uci, variant::VariantPosition, CastlingMode, Position};
 
 use crate::{
+    api::Work,
     model::MultiPv,
-    uci::{Eval, UciOut},
+    uci::{Eval, UciOut, Wdl},
 };
 
 #[serde_as]
@@ -16,11 +17,17 @@ struct EmitPv {
     moves: Vec<Uci>,
     #[serde(flatten)]
     eval: Eval,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    wdl: Option<Wdl>,
     depth: u32,
 }
 
 impl EmitPv {
-    fn extract(uci: &UciOut, pos: &VariantPosition) -> (MultiPv, Option<EmitPv>) {
+    fn extract(
+        uci: &UciOut,
+        pos: &VariantPosition,
+        max_pv_moves: usize,
+    ) -> (MultiPv, Option<EmitPv>) {
         let multi_pv = match *uci {
             UciOut::Info {
                 multipv: Some(multipv),
@@ -39,8 +46,9 @@ impl EmitPv {
                     ..
                 } => (multi_pv > MultiPv::default() || (!score.lowerbound && !score.upperbound))
                     .then(|| EmitPv {
-                        moves: normalize_pv(pv, pos.clone()),
+                        moves: normalize_pv(pv, pos.clone(), max_pv_moves),
                         eval: pos.turn().fold_wb(score.eval, -score.eval),
+                        wdl: score.wdl.map(|wdl| pos.turn().fold_wb(wdl, -wdl)),
                         depth,
                     }),
                 _ => None,
@@ -49,9 +57,9 @@ impl EmitPv {
     }
 }
 
-fn normalize_pv(pv: &[Uci], mut pos: VariantPosition) -> Vec<Uci> {
+fn normalize_pv(pv: &[Uci], mut pos: VariantPosition, max_moves: usize) -> Vec<Uci> {
     let mut moves = Vec::new();
-    for uci in pv.iter().take(30) {
+    for uci in pv.iter().take(max_moves) {
         let m = match uci.to_move(&pos) {
             Ok(m) => m,
             Err(_) => break,
@@ -62,6 +70,71 @@ fn normalize_pv(pv: &[Uci], mut pos: VariantPosition) -> Vec<Uci> {
     moves
 }
 
+/// Search statistics that are only emitted on request. Each field keeps the
+/// latest value reported by the engine.
+#[serde_as]
+#[derive(Clone, Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct EmitInfo {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seldepth: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    nps: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hashfull: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tbhits: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sbhits: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cpuload: Option<u32>,
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    currmove: Option<Uci>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    currmovenumber: Option<u32>,
+}
+
+impl EmitInfo {
+    fn update(&mut self, uci: &UciOut, pos: &VariantPosition) {
+        if let UciOut::Info {
+            seldepth,
+            hashfull,
+            nps,
+            tbhits,
+            sbhits,
+            cpuload,
+            ref currmove,
+            currmovenumber,
+            ..
+        } = *uci
+        {
+            self.seldepth = seldepth.or(self.seldepth);
+            self.nps = nps.or(self.nps);
+            self.hashfull = hashfull.or(self.hashfull);
+            self.tbhits = tbhits.or(self.tbhits);
+            self.sbhits = sbhits.or(self.sbhits);
+            self.cpuload = cpuload.or(self.cpuload);
+            if let Some(currmove) = currmove.as_ref().and_then(|uci| uci.to_move(pos).ok()) {
+                self.currmove = Some(currmove.to_uci(CastlingMode::Chess960));
+            }
+            self.currmovenumber = currmovenumber.or(self.currmovenumber);
+        }
+    }
+}
+
+/// Result of the search, only present in the final emit. A missing best
+/// move means there were no legal moves.
+#[serde_as]
+#[derive(Clone, Debug, Serialize)]
+struct EmitBest {
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    bestmove: Option<Uci>,
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ponder: Option<Uci>,
+}
+
 #[serde_as]
 #[derive(Clone, Debug, Default, Serialize)]
 pub struct Emit {
@@ -69,12 +142,59 @@ pub struct Emit {
     time: Duration,
     depth: u32,
     nodes: u64,
+    /// Intermediate emits have all principal variations. The final emit
+    /// only has the complete ones, if any.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
     pvs: Vec<Option<EmitPv>>,
+    #[serde(flatten)]
+    info: Option<EmitInfo>,
+    /// `info string` lines since the previous emit, if requested.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    strings: Option<Vec<String>>,
+    #[serde(flatten)]
+    best: Option<EmitBest>,
 }
 
 impl Emit {
-    pub fn update(&mut self, uci: &UciOut, pos: &VariantPosition) {
-        let (multi_pv, emit_pv) = EmitPv::extract(uci, pos);
+    /// Starts an empty emit, with the optional parts requested by the work.
+    pub fn new(work: &Work) -> Emit {
+        Emit {
+            info: work.extended_info().then(EmitInfo::default),
+            strings: work.info_strings().then(Vec::new),
+            ..Emit::default()
+        }
+    }
+
+    pub fn update(&mut self, uci: &UciOut, pos: &VariantPosition, max_pv_moves: usize) {
+        if let Some(ref mut info) = self.info {
+            info.update(uci, pos);
+        }
+        match *uci {
+            UciOut::Bestmove { ref m, ref ponder } => {
+                let moves = normalize_pv(
+                    &m.iter().chain(ponder).cloned().collect::<Vec<_>>(),
+                    pos.clone(),
+                    2,
+                );
+                let mut moves = moves.into_iter();
+                self.best = Some(EmitBest {
+                    bestmove: moves.next(),
+                    ponder: moves.next(),
+                });
+                self.pvs.retain(Option::is_some);
+                return;
+            }
+            UciOut::Info {
+                string: Some(ref string),
+                ..
+            } => {
+                if let Some(ref mut strings) = self.strings {
+                    strings.push(string.clone());
+                }
+            }
+            _ => (),
+        }
+        let (multi_pv, emit_pv) = EmitPv::extract(uci, pos, max_pv_moves);
         if multi_pv <= MultiPv::default() {
             if let UciOut::Info {
                 time: Some(time), ..
@@ -114,7 +234,39 @@ impl Emit {
         }
     }
 
+    /// The search is over, and this emit includes its result.
+    pub fn is_final(&self) -> bool {
+        self.best.is_some()
+    }
+
     pub fn should_emit(&self) -> bool {
         !self.pvs.is_empty() && self.pvs.iter().all(|pv| pv.is_some())
     }
+
+    /// Completes the emit with the best move and ponder move of the first
+    /// principal variation, when the search was stopped before the engine
+    /// reported a best move.
+    pub fn finish(&mut self) {
+        if self.best.is_none() {
+            let mut moves = self
+                .pvs
+                .first()
+                .and_then(Option::as_ref)
+                .map(|pv| pv.moves.clone())
+                .unwrap_or_default()
+                .into_iter();
+            self.best = Some(EmitBest {
+                bestmove: moves.next(),
+                ponder: moves.next(),
+            });
+            self.pvs.retain(Option::is_some);
+        }
+    }
+
+    /// Forgets `info string` lines that have been emitted.
+    pub fn emitted(&mut self) {
+        if let Some(ref mut strings) = self.strings {
+            strings.clear();
+        }
+    }
 }
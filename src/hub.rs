@@ -1,120 +1,448 @@
 use std::{
-    array,
-    collections::{hash_map::RandomState, HashMap, VecDeque},
-    hash::{BuildHasher, Hash, Hasher},
-    sync::{Arc, Mutex},
+    collections::{hash_map::RandomState, BTreeMap, HashMap, VecDeque},
+    hash::{BuildHasher, Hash},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
     time::Duration,
 };
 
-use tokio::{sync::Notify, time::sleep};
-
-const NUM_SHARDS: usize = 64;
-
-const MAX_ITEMS: usize = 1024;
+use serde::Serialize;
+use serde_with::{serde_as, DurationMilliSeconds};
+use tokio::{
+    pin,
+    sync::Notify,
+    time::{sleep, Instant},
+};
 
 pub trait IsValid {
     fn is_valid(&self) -> bool;
 }
 
-pub struct Hub<S, R> {
+/// How items are ordered in a queue: higher priorities go first, and within
+/// the same priority, owners take turns, so that no single owner can starve
+/// the others.
+pub trait Schedule {
+    type Owner: Hash + Eq + Clone;
+    type Priority: Ord + Copy;
+
+    fn owner(&self) -> Self::Owner;
+
+    fn priority(&self) -> Self::Priority;
+}
+
+/// Records when a garbage collector last made progress, so that a stuck or
+/// crashed collector task can be detected.
+#[derive(Default)]
+pub struct Heartbeat(Mutex<Option<Instant>>);
+
+impl Heartbeat {
+    pub fn beat(&self) {
+        *self.0.lock().unwrap() = Some(Instant::now());
+    }
+
+    pub fn elapsed(&self) -> Option<Duration> {
+        self.0.lock().unwrap().map(|last| last.elapsed())
+    }
+}
+
+#[serde_as]
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ShardStats {
+    pub shards: usize,
+    pub entries: usize,
+    pub max_shard_entries: usize,
+    #[serde_as(as = "Option<DurationMilliSeconds>")]
+    pub since_garbage_collection: Option<Duration>,
+}
+
+impl ShardStats {
+    pub fn new(entries: impl ExactSizeIterator<Item = usize>, heartbeat: &Heartbeat) -> ShardStats {
+        let shards = entries.len();
+        let (entries, max_shard_entries) =
+            entries.fold((0, 0), |(sum, max), n| (sum + n, max.max(n)));
+        ShardStats {
+            shards,
+            entries,
+            max_shard_entries,
+            since_garbage_collection: heartbeat.elapsed(),
+        }
+    }
+
+    /// Whether the garbage collector has run recently.
+    pub fn is_collecting(&self, max_interval: Duration) -> bool {
+        matches!(self.since_garbage_collection, Some(elapsed) if elapsed <= max_interval)
+    }
+}
+
+/// The queue was full, so the item was handed back.
+#[derive(Debug)]
+pub struct QueueFull<R>(pub R);
+
+pub struct Hub<S, R: Schedule> {
     random_state: RandomState,
-    shards: [Mutex<Shard<S, R>>; NUM_SHARDS],
+    shards: Box<[Mutex<Shard<S, R>>]>,
+    max_items: usize,
+    rejected: AtomicU64,
+    heartbeat: Heartbeat,
 }
 
-impl<S: Hash + Eq, R: IsValid> Default for Hub<S, R> {
-    fn default() -> Hub<S, R> {
+impl<S: Hash + Eq, R: IsValid + Schedule> Hub<S, R> {
+    pub fn new(num_shards: usize, max_items: usize) -> Hub<S, R> {
         Hub {
             random_state: RandomState::new(),
-            shards: array::from_fn(|_| Mutex::new(Shard::new())),
+            shards: (0..num_shards.max(1))
+                .map(|_| Mutex::new(Shard::new()))
+                .collect(),
+            max_items: max_items.max(1),
+            rejected: AtomicU64::new(0),
+            heartbeat: Heartbeat::default(),
         }
     }
 }
 
-impl<S: Hash + Eq + Clone, R: IsValid> Hub<S, R> {
-    pub fn submit(&self, selector: S, data: R) {
+impl<S: Hash + Eq + Clone, R: IsValid + Schedule> Hub<S, R> {
+    /// Queues an item, or hands it back if the queue is full even after
+    /// dropping items that are no longer valid.
+    pub fn submit(&self, selector: S, data: R) -> Result<(), QueueFull<R>> {
         let shard = self.shard(&selector);
-        shard.lock().unwrap().submit(selector, data);
+        let res = shard.lock().unwrap().submit(selector, data, self.max_items);
+        if res.is_err() {
+            self.rejected.fetch_add(1, Ordering::Relaxed);
+        }
+        res
     }
 
-    pub async fn acquire(&self, selector: S) -> R {
+    /// Waits for the next item that `accept` is willing to take.
+    pub async fn acquire(&self, selector: S, accept: impl Fn(&R) -> bool) -> R {
         let shard = self.shard(&selector);
+        let signal = shard.lock().unwrap().signal(selector.clone());
         loop {
-            let res = shard.lock().unwrap().acquire(selector.clone());
-            match res {
-                Ok(item) => return item,
-                Err(signal) => signal.notified().await,
+            // Register interest before looking at the queue, so that no
+            // submission in between is missed.
+            let notified = signal.notified();
+            pin!(notified);
+            notified.as_mut().enable();
+            if let Some(item) = shard.lock().unwrap().acquire(&selector, &accept) {
+                return item;
             }
+            notified.await;
+        }
+    }
+
+    /// Number of queued items per selector, including items that are no
+    /// longer valid but have not been collected yet.
+    pub fn queue_depths(&self) -> Vec<(S, usize)> {
+        let mut depths = Vec::new();
+        for shard in self.shards.iter() {
+            let shard = shard.lock().unwrap();
+            depths.extend(
+                shard
+                    .map
+                    .iter()
+                    .filter(|(_, queue)| queue.len > 0)
+                    .map(|(selector, queue)| (selector.clone(), queue.len)),
+            );
         }
+        depths
+    }
+
+    /// Queued items per shard, including items that are no longer valid but
+    /// have not been collected yet.
+    pub fn stats(&self) -> ShardStats {
+        ShardStats::new(
+            self.shards.iter().map(|shard| {
+                shard
+                    .lock()
+                    .unwrap()
+                    .map
+                    .values()
+                    .map(|queue| queue.len)
+                    .sum()
+            }),
+            &self.heartbeat,
+        )
+    }
+
+    /// Number of items refused because their queue was full.
+    pub fn rejected(&self) -> u64 {
+        self.rejected.load(Ordering::Relaxed)
     }
 
     fn shard(&self, selector: &S) -> &Mutex<Shard<S, R>> {
-        let mut hasher = self.random_state.build_hasher();
-        selector.hash(&mut hasher);
-        &self.shards[hasher.finish() as usize % NUM_SHARDS]
+        &self.shards[self.random_state.hash_one(selector) as usize % self.shards.len()]
     }
 }
 
-impl<S, R: IsValid> Hub<S, R> {
-    pub async fn garbage_collect(&self) {
+impl<S, R: IsValid + Schedule> Hub<S, R> {
+    pub async fn garbage_collect(&self, interval: Duration) {
         loop {
-            for shard in &self.shards {
+            for shard in self.shards.iter() {
                 shard.lock().unwrap().garbage_collect();
-                sleep(Duration::from_secs(13)).await;
+                self.heartbeat.beat();
+                sleep(interval).await;
             }
         }
     }
 }
 
-struct Shard<S, R> {
+struct Shard<S, R: Schedule> {
     map: HashMap<S, Queue<R>>,
 }
 
-impl<S: Eq + Hash, R: IsValid> Shard<S, R> {
+impl<S: Eq + Hash, R: IsValid + Schedule> Shard<S, R> {
     fn new() -> Shard<S, R> {
         Shard {
             map: HashMap::new(),
         }
     }
 
-    fn submit(&mut self, selector: S, data: R) {
+    fn submit(&mut self, selector: S, data: R, max_items: usize) -> Result<(), QueueFull<R>> {
         let entry = self.map.entry(selector).or_default();
-        if entry.inner.len() < MAX_ITEMS {
-            entry.inner.push_back(data);
-            entry.signal.notify_one();
+        if entry.len >= max_items {
+            entry.retain_valid();
+        }
+        if entry.len >= max_items {
+            return Err(QueueFull(data));
         }
+        entry.push(data);
+        // Not every waiter may accept the new item, so let all of them look.
+        entry.signal.notify_waiters();
+        Ok(())
     }
 
-    fn acquire(&mut self, selector: S) -> Result<R, Arc<Notify>> {
-        let entry = self.map.entry(selector).or_default();
+    fn signal(&mut self, selector: S) -> Arc<Notify> {
+        Arc::clone(&self.map.entry(selector).or_default().signal)
+    }
+
+    fn acquire(&mut self, selector: &S, accept: impl Fn(&R) -> bool) -> Option<R> {
+        let entry = self.map.get_mut(selector)?;
         loop {
-            match entry.inner.pop_front() {
-                Some(item) if item.is_valid() => return Ok(item),
+            match entry.pop(&accept) {
+                Some(item) if item.is_valid() => return Some(item),
                 Some(_) => continue,
-                None => return Err(Arc::clone(&entry.signal)),
+                None => return None,
             }
         }
     }
 }
 
-impl<S, R: IsValid> Shard<S, R> {
+impl<S, R: IsValid + Schedule> Shard<S, R> {
     fn garbage_collect(&mut self) {
         self.map.retain(|_, queue| {
-            queue.inner.retain(|item| item.is_valid());
-            !queue.inner.is_empty()
+            queue.retain_valid();
+            // Keep the signal of queues that are still being waited on.
+            queue.len > 0 || Arc::strong_count(&queue.signal) > 1
         });
     }
 }
 
-struct Queue<R> {
+struct Queue<R: Schedule> {
     signal: Arc<Notify>,
-    inner: VecDeque<R>,
+    lanes: BTreeMap<R::Priority, Lane<R>>,
+    len: usize,
 }
 
-impl<R> Default for Queue<R> {
+impl<R: Schedule> Default for Queue<R> {
     fn default() -> Queue<R> {
         Queue {
             signal: Arc::new(Notify::new()),
-            inner: VecDeque::new(),
+            lanes: BTreeMap::new(),
+            len: 0,
+        }
+    }
+}
+
+impl<R: IsValid + Schedule> Queue<R> {
+    fn push(&mut self, item: R) {
+        self.lanes.entry(item.priority()).or_default().push(item);
+        self.len += 1;
+    }
+
+    /// Removes the next item that `accept` is willing to take, from the
+    /// highest priority lane that has one.
+    fn pop(&mut self, accept: impl Fn(&R) -> bool) -> Option<R> {
+        let (priority, item) = self
+            .lanes
+            .iter_mut()
+            .rev()
+            .find_map(|(priority, lane)| lane.pop(&accept).map(|item| (*priority, item)))?;
+        if self.lanes[&priority].is_empty() {
+            self.lanes.remove(&priority);
+        }
+        self.len -= 1;
+        Some(item)
+    }
+
+    fn retain_valid(&mut self) {
+        for lane in self.lanes.values_mut() {
+            self.len -= lane.retain_valid();
+        }
+        self.lanes.retain(|_, lane| !lane.is_empty());
+    }
+}
+
+/// Items of the same priority. An owner is in `turns` exactly if it has
+/// items.
+struct Lane<R: Schedule> {
+    turns: VecDeque<R::Owner>,
+    items: HashMap<R::Owner, VecDeque<R>>,
+}
+
+impl<R: Schedule> Default for Lane<R> {
+    fn default() -> Lane<R> {
+        Lane {
+            turns: VecDeque::new(),
+            items: HashMap::new(),
+        }
+    }
+}
+
+impl<R: IsValid + Schedule> Lane<R> {
+    fn is_empty(&self) -> bool {
+        self.turns.is_empty()
+    }
+
+    fn push(&mut self, item: R) {
+        let owner = item.owner();
+        let items = self.items.entry(owner.clone()).or_default();
+        if items.is_empty() {
+            self.turns.push_back(owner);
+        }
+        items.push_back(item);
+    }
+
+    /// Removes the oldest acceptable item of the first owner in turn that
+    /// has one.
+    fn pop(&mut self, accept: impl Fn(&R) -> bool) -> Option<R> {
+        let turn = self
+            .turns
+            .iter()
+            .position(|owner| self.items[owner].iter().any(&accept))?;
+        let owner = self.turns.remove(turn)?;
+        let items = self.items.get_mut(&owner)?;
+        let item = items.remove(items.iter().position(&accept)?);
+        if items.is_empty() {
+            self.items.remove(&owner);
+        } else {
+            self.turns.push_back(owner);
+        }
+        item
+    }
+
+    /// Drops items that are no longer valid, and returns how many.
+    fn retain_valid(&mut self) -> usize {
+        let mut removed = 0;
+        self.items.retain(|_, items| {
+            let before = items.len();
+            items.retain(|item| item.is_valid());
+            removed += before - items.len();
+            !items.is_empty()
+        });
+        let items = &self.items;
+        self.turns.retain(|owner| items.contains_key(owner));
+        removed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq, Eq)]
+    struct Item {
+        owner: char,
+        priority: u8,
+        n: u32,
+    }
+
+    impl IsValid for Item {
+        fn is_valid(&self) -> bool {
+            self.n != 0
+        }
+    }
+
+    impl Schedule for Item {
+        type Owner = char;
+        type Priority = u8;
+
+        fn owner(&self) -> char {
+            self.owner
+        }
+
+        fn priority(&self) -> u8 {
+            self.priority
+        }
+    }
+
+    fn item(owner: char, priority: u8, n: u32) -> Item {
+        Item { owner, priority, n }
+    }
+
+    fn drain(queue: &mut Queue<Item>) -> Vec<(char, u32)> {
+        let mut order = Vec::new();
+        while let Some(item) = queue.pop(|_| true) {
+            order.push((item.owner, item.n));
+        }
+        assert_eq!(queue.len, 0);
+        order
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let mut queue = Queue::default();
+        for n in 1..=3 {
+            queue.push(item('a', 0, n));
         }
+        queue.push(item('b', 0, 1));
+        queue.push(item('c', 0, 1));
+        queue.push(item('b', 0, 2));
+        assert_eq!(queue.len, 6);
+        assert_eq!(
+            drain(&mut queue),
+            [('a', 1), ('b', 1), ('c', 1), ('a', 2), ('b', 2), ('a', 3)]
+        );
+    }
+
+    #[test]
+    fn test_priority() {
+        let mut queue = Queue::default();
+        queue.push(item('a', 0, 1));
+        queue.push(item('a', 0, 2));
+        queue.push(item('b', 1, 1));
+        queue.push(item('a', 1, 3));
+        assert_eq!(drain(&mut queue), [('b', 1), ('a', 3), ('a', 1), ('a', 2)]);
+    }
+
+    #[test]
+    fn test_retain_valid() {
+        let mut queue = Queue::default();
+        queue.push(item('a', 1, 1));
+        queue.push(item('b', 0, 1));
+        queue.push(item('c', 0, 0));
+        queue.push(item('c', 0, 2));
+        queue.push(item('c', 0, 3));
+        queue.push(item('b', 0, 0));
+        queue.retain_valid();
+        assert_eq!(queue.len, 4);
+        assert_eq!(drain(&mut queue), [('a', 1), ('b', 1), ('c', 2), ('c', 3)]);
+    }
+
+    #[test]
+    fn test_accept() {
+        let mut queue = Queue::default();
+        queue.push(item('a', 1, 10));
+        queue.push(item('a', 1, 1));
+        queue.push(item('b', 1, 2));
+        queue.push(item('c', 0, 3));
+        let small = |item: &Item| item.n < 10;
+        assert_eq!(queue.pop(small), Some(item('a', 1, 1)));
+        assert_eq!(queue.pop(small), Some(item('b', 1, 2)));
+        assert_eq!(queue.pop(small), Some(item('c', 0, 3)));
+        assert_eq!(queue.pop(small), None);
+        assert_eq!(queue.len, 1);
+        assert_eq!(drain(&mut queue), [('a', 10)]);
     }
 }
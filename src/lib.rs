@@ -0,0 +1,1184 @@
+use std::{collections::HashMap, io, sync::Arc, time::Duration};
+
+use axum::{
+    extract::{
+        ws::{
+            close_code, rejection::WebSocketUpgradeRejection, CloseFrame, Message, WebSocket,
+            WebSocketUpgrade,
+        },
+        BodyStream, FromRef, Json, State,
+    },
+    http::{
+        header::{HeaderMap, HeaderName, ACCEPT, RETRY_AFTER},
+        StatusCode,
+    },
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+    Router,
+};
+use axum_extra::{
+    json_lines,
+    json_lines::JsonLines,
+    routing::{RouterExt, TypedPath},
+};
+use futures::{
+    future::{self, BoxFuture},
+    stream::{self, BoxStream, Stream},
+    Future, FutureExt,
+};
+use futures_util::stream::{StreamExt, TryStreamExt};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use shakmaty::variant::VariantPosition;
+use thiserror::Error;
+use tokio::{
+    io::AsyncBufReadExt,
+    pin, select,
+    sync::{
+        mpsc::{self, error::TrySendError},
+        oneshot::{self, error::RecvError},
+    },
+    task,
+    time::{error::Elapsed, sleep, timeout, Instant},
+};
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_util::{io::StreamReader, sync::CancellationToken};
+use tower_http::{cors::CorsLayer, trace::TraceLayer};
+
+use crate::{
+    api::{
+        AcquireRequest, AcquireResponse, AnalyseRequest, CancelRequest, Capacity, ClientHello,
+        ClientMessage, ClientWork, Instance, InvalidWorkError, Priority, SocketHello, SocketLine,
+        SocketMessage, Work,
+    },
+    emit::Emit,
+    hub::{Hub, IsValid, QueueFull, Schedule, ShardStats},
+    limits::Limits,
+    metrics::Metrics,
+    model::{Engine, EngineId, InstanceId, JobId, ProviderSelector, SessionId, UserId},
+    ongoing::Ongoing,
+    progress::{Progress, ProgressGuard, Snapshot, Status},
+    rate_limit::RateLimiter,
+    repo::{EngineRegistry, RegistryError},
+    uci::UciOut,
+};
+
+pub mod api;
+pub mod emit;
+pub mod hub;
+pub mod limits;
+pub mod metrics;
+pub mod model;
+pub mod ongoing;
+pub mod progress;
+pub mod provider;
+pub mod rate_limit;
+pub mod repo;
+pub mod simulator;
+pub mod uci;
+
+pub struct Job {
+    id: JobId,
+    tx: oneshot::Sender<mpsc::Receiver<Result<Emit, Error>>>,
+    pos: VariantPosition,
+    selector: ProviderSelector,
+    engine: Engine,
+    work: Work,
+    progress: ProgressGuard,
+    acquired_by: Option<Instance>,
+    requeues: u32,
+    /// Instances that acquired the job but never submitted.
+    avoid: Vec<InstanceId>,
+}
+
+impl IsValid for Job {
+    fn is_valid(&self) -> bool {
+        !self.tx.is_closed() && !self.progress.is_cancelled()
+    }
+}
+
+impl Schedule for Job {
+    type Owner = UserId;
+    type Priority = Priority;
+
+    fn owner(&self) -> UserId {
+        self.engine.config.user_id.clone()
+    }
+
+    fn priority(&self) -> Priority {
+        self.work.priority()
+    }
+}
+
+/// A provider instance is considered alive until its lease expires. The
+/// lease is renewed whenever the instance acquires or submits work.
+#[derive(Clone)]
+pub struct InstanceLease {
+    expires_at: Instant,
+    capacity: Capacity,
+}
+
+impl InstanceLease {
+    fn new(timeout: Duration, capacity: Capacity) -> InstanceLease {
+        InstanceLease {
+            expires_at: Instant::now() + timeout,
+            capacity,
+        }
+    }
+}
+
+impl IsValid for InstanceLease {
+    fn is_valid(&self) -> bool {
+        Instant::now() < self.expires_at
+    }
+}
+
+#[derive(Clone)]
+pub struct AppState {
+    pub repo: &'static dyn EngineRegistry,
+    pub limits: &'static Limits,
+    pub hub: &'static Hub<ProviderSelector, Job>,
+    pub ongoing: &'static Ongoing<JobId, Job>,
+    pub sessions: &'static Ongoing<(EngineId, SessionId), Arc<Progress>>,
+    pub progress: &'static Ongoing<JobId, Arc<Progress>>,
+    pub instances: &'static Ongoing<(ProviderSelector, InstanceId), InstanceLease>,
+    pub metrics: &'static Metrics,
+    pub engine_rate: &'static RateLimiter<EngineId>,
+    pub user_rate: &'static RateLimiter<UserId>,
+    pub draining: CancellationToken,
+}
+
+impl AppState {
+    pub fn new(repo: &'static dyn EngineRegistry, limits: Limits) -> AppState {
+        AppState {
+            repo,
+            hub: Box::leak(Box::new(Hub::new(limits.hub_shards, limits.max_queued))),
+            engine_rate: Box::leak(Box::new(RateLimiter::new(limits.engine_rate))),
+            user_rate: Box::leak(Box::new(RateLimiter::new(limits.user_rate))),
+            limits: Box::leak(Box::new(limits)),
+            ongoing: Box::leak(Box::new(Ongoing::default())),
+            sessions: Box::leak(Box::new(Ongoing::default())),
+            progress: Box::leak(Box::new(Ongoing::default())),
+            instances: Box::leak(Box::new(Ongoing::default())),
+            metrics: Box::leak(Box::new(Metrics::default())),
+            draining: CancellationToken::new(),
+        }
+    }
+
+    /// Stops handing out new work, while letting jobs that are already being
+    /// submitted run to completion.
+    pub fn drain(&self) {
+        self.draining.cancel();
+    }
+
+    /// Whether all acquired jobs have started submitting, or are no longer
+    /// wanted. Submissions in progress keep their connection open, so the
+    /// server can then stop accepting new connections.
+    pub fn is_drained(&self) -> bool {
+        !self.ongoing.any(|_, job| job.is_valid())
+    }
+
+    fn renew_lease(&self, selector: &ProviderSelector, instance: &Instance) {
+        self.instances.add(
+            (selector.clone(), instance.id.clone()),
+            InstanceLease::new(self.limits.instance_timeout, instance.capacity.clone()),
+        );
+    }
+
+    /// Whether an instance can take a job. Instances that acquired the job
+    /// before but never submitted it only get it again if no other live
+    /// instance can take it.
+    fn fits(&self, job: &Job, instance: Option<&Instance>) -> bool {
+        let Some(instance) = instance else {
+            return true;
+        };
+        job.work.fits(&instance.capacity)
+            && (!job.avoid.contains(&instance.id)
+                || !self.instances.any(|(selector, id), lease| {
+                    *selector == job.selector
+                        && !job.avoid.contains(id)
+                        && lease.is_valid()
+                        && job.work.fits(&lease.capacity)
+                }))
+    }
+}
+
+impl FromRef<AppState> for &'static dyn EngineRegistry {
+    fn from_ref(state: &AppState) -> &'static dyn EngineRegistry {
+        state.repo
+    }
+}
+
+impl FromRef<AppState> for &'static Limits {
+    fn from_ref(state: &AppState) -> &'static Limits {
+        state.limits
+    }
+}
+
+impl FromRef<AppState> for &'static Hub<ProviderSelector, Job> {
+    fn from_ref(state: &AppState) -> &'static Hub<ProviderSelector, Job> {
+        state.hub
+    }
+}
+
+impl FromRef<AppState> for &'static Ongoing<JobId, Job> {
+    fn from_ref(state: &AppState) -> &'static Ongoing<JobId, Job> {
+        state.ongoing
+    }
+}
+
+impl FromRef<AppState> for &'static Ongoing<(EngineId, SessionId), Arc<Progress>> {
+    fn from_ref(state: &AppState) -> &'static Ongoing<(EngineId, SessionId), Arc<Progress>> {
+        state.sessions
+    }
+}
+
+impl FromRef<AppState> for &'static Ongoing<JobId, Arc<Progress>> {
+    fn from_ref(state: &AppState) -> &'static Ongoing<JobId, Arc<Progress>> {
+        state.progress
+    }
+}
+
+impl FromRef<AppState> for &'static Metrics {
+    fn from_ref(state: &AppState) -> &'static Metrics {
+        state.metrics
+    }
+}
+
+impl FromRef<AppState> for CancellationToken {
+    fn from_ref(state: &AppState) -> CancellationToken {
+        state.draining.clone()
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("registry error: {0}")]
+    Registry(#[from] RegistryError),
+    #[error("engine not found or invalid clientSecret")]
+    EngineNotFound,
+    #[error("work not found or cancelled or expired")]
+    WorkNotFound,
+    #[error("i/o error: {0}")]
+    Io(#[from] io::Error),
+    #[error("uci protocol error: {0}")]
+    Protocol(#[from] uci::ProtocolError),
+    #[error("invalid work: {0}")]
+    InvalidWork(#[from] InvalidWorkError),
+    #[error("provider did not pick up work")]
+    ProviderTimeout,
+    #[error("provider busy")]
+    ProviderBusy,
+    #[error("provider acquired work but never submitted it")]
+    ProviderFailed,
+    #[error("provider disconnected during analysis")]
+    ProviderDisconnected,
+    #[error("work cancelled")]
+    Cancelled,
+    #[error("shutting down")]
+    ShuttingDown,
+    #[error("websocket upgrade required")]
+    UpgradeRequired,
+    #[error("too many requests, retry in {}s", retry_after_secs(*.0))]
+    RateLimited(Duration),
+}
+
+fn retry_after_secs(wait: Duration) -> u64 {
+    wait.as_secs() + u64::from(wait.subsec_nanos() > 0)
+}
+
+impl IntoResponse for Error {
+    fn into_response(self) -> Response {
+        let status = match self {
+            Error::Registry(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Error::Io(_) | Error::Protocol(_) | Error::InvalidWork(_) => StatusCode::BAD_REQUEST,
+            Error::EngineNotFound | Error::WorkNotFound => StatusCode::NOT_FOUND,
+            Error::ProviderTimeout | Error::ProviderBusy | Error::ShuttingDown => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
+            Error::ProviderFailed | Error::ProviderDisconnected => StatusCode::BAD_GATEWAY,
+            Error::Cancelled => StatusCode::GONE,
+            Error::UpgradeRequired => StatusCode::UPGRADE_REQUIRED,
+            Error::RateLimited(wait) => {
+                return (
+                    StatusCode::TOO_MANY_REQUESTS,
+                    [(RETRY_AFTER, retry_after_secs(wait).to_string())],
+                    self.to_string(),
+                )
+                    .into_response()
+            }
+        };
+        (status, self.to_string()).into_response()
+    }
+}
+
+pub fn router(state: AppState) -> Router {
+    Router::new()
+        .typed_post(analyse)
+        .typed_post(cancel)
+        .typed_post(acquire)
+        .typed_post(submit)
+        .typed_get(provider_socket)
+        .typed_get(client_socket)
+        .typed_get(status)
+        .typed_get(health)
+        .typed_get(ready)
+        .layer(CorsLayer::permissive().max_age(Duration::from_secs(60 * 60 * 24)))
+        .layer(TraceLayer::new_for_http())
+        .with_state(state)
+}
+
+/// Separate router for internal metrics, so that they can be bound to an
+/// address that is not publicly reachable.
+pub fn metrics_router(state: AppState) -> Router {
+    Router::new().typed_get(scrape).with_state(state)
+}
+
+#[derive(TypedPath, Deserialize)]
+#[typed_path("/api/external-engine/:id/analyse")]
+struct AnalysePath {
+    id: EngineId,
+}
+
+static JOB_ID: HeaderName = HeaderName::from_static("x-job-id");
+
+#[axum_macros::debug_handler(state = AppState)]
+async fn analyse(
+    AnalysePath { id }: AnalysePath,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(req): Json<AnalyseRequest>,
+) -> Result<Response, Error> {
+    if state.draining.is_cancelled() {
+        return Err(Error::ShuttingDown);
+    }
+    let (engine, provider_selector) = state
+        .repo
+        .find(id, req.client_secret)
+        .await?
+        .ok_or(Error::EngineNotFound)?
+        .into_engine_and_selector();
+    let Analysis { id, emits } = start_analysis(state, engine, provider_selector, req.work).await?;
+    let job_id = [(JOB_ID.clone(), id.to_string())];
+    Ok(if wants_event_stream(&headers) {
+        let events = emits.map(|emit| {
+            Event::default()
+                .json_data(emit.map_err(axum::Error::new)?)
+                .map_err(axum::Error::new)
+        });
+        (job_id, Sse::new(events).keep_alive(KeepAlive::default())).into_response()
+    } else {
+        let lines: JsonLines<_, json_lines::AsResponse> = JsonLines::new(emits);
+        (job_id, lines).into_response()
+    })
+}
+
+fn wants_event_stream(headers: &HeaderMap) -> bool {
+    matches!(
+        headers.get(ACCEPT).map(|accept| accept.to_str()),
+        Some(Ok(accept)) if accept.contains("text/event-stream")
+    )
+}
+
+/// A job that a provider is working on, or a job that is being resumed.
+struct Analysis {
+    id: JobId,
+    emits: BoxStream<'static, Result<Emit, Error>>,
+}
+
+/// Queues work for a provider and waits until it is picked up, or resumes
+/// the same work of the same session.
+async fn start_analysis(
+    state: AppState,
+    engine: Engine,
+    provider_selector: ProviderSelector,
+    work: Work,
+) -> Result<Analysis, Error> {
+    let AppState {
+        limits,
+        hub,
+        sessions,
+        progress: jobs,
+        metrics,
+        engine_rate,
+        user_rate,
+        draining,
+        ..
+    } = state;
+    // Only spend a token from either bucket if both have one.
+    if let Err(wait) = user_rate
+        .peek(&engine.config.user_id)
+        .and_then(|()| engine_rate.check(engine.id.clone()))
+        .and_then(|()| user_rate.check(engine.config.user_id.clone()))
+    {
+        metrics.rate_limited.inc();
+        return Err(Error::RateLimited(wait));
+    }
+    let (work, pos) = work.sanitize(&engine, limits)?;
+    metrics.analyse.inc();
+    let session = (engine.id.clone(), work.session_id().clone());
+    if let Some(progress) = sessions
+        .get(&session)
+        .filter(|progress| progress.is_valid() && progress.is_resumable())
+        .filter(|progress| *progress.work() == work)
+    {
+        log::info!("resuming {}", progress.id());
+        metrics.analyse_resumed.inc();
+        return Ok(Analysis {
+            id: progress.id().clone(),
+            emits: progress.replay().map(Ok).boxed(),
+        });
+    }
+    let id = JobId::random();
+    let progress = Arc::new(Progress::new(id.clone(), work.clone()));
+    if let Some(previous) = sessions
+        .add(session, Arc::clone(&progress))
+        .filter(|previous| previous.is_live())
+    {
+        log::info!("{} superseded by {}", previous.id(), id);
+        metrics.superseded.inc();
+        previous.cancel();
+    }
+    jobs.add(id.clone(), Arc::clone(&progress));
+    let (tx, rx) = oneshot::channel();
+    if let Err(QueueFull(_)) = hub.submit(
+        provider_selector.clone(),
+        Job {
+            id: id.clone(),
+            tx,
+            selector: provider_selector,
+            engine,
+            work,
+            pos,
+            progress: ProgressGuard::new(Arc::clone(&progress)),
+            acquired_by: None,
+            requeues: 0,
+            avoid: Vec::new(),
+        },
+    ) {
+        log::warn!("queue full, rejected {}", id);
+        return Err(Error::ProviderBusy);
+    }
+    let received = timeout(limits.provider_timeout, rx);
+    pin!(received);
+    let mut drained = false;
+    let rx = loop {
+        select! {
+            res = &mut received => break res.map_err(|_: Elapsed| {
+                metrics.provider_timeouts.inc();
+                Error::ProviderTimeout
+            })?.map_err(|_: RecvError| match progress.snapshot().status {
+                Status::Failed => Error::ProviderFailed,
+                Status::Cancelled => Error::Cancelled,
+                _ => Error::ProviderBusy,
+            })?,
+            _ = progress.cancelled() => return Err(Error::Cancelled),
+            _ = draining.cancelled(), if !drained => {
+                // Work that a provider acquired before may still be
+                // submitted.
+                if progress.snapshot().status == Status::Queued {
+                    return Err(Error::ShuttingDown);
+                }
+                drained = true;
+            }
+        }
+    };
+    Ok(Analysis {
+        id,
+        emits: ReceiverStream::new(rx)
+            .take_until(async move { progress.cancelled().await })
+            .boxed(),
+    })
+}
+
+#[derive(TypedPath, Deserialize)]
+#[typed_path("/api/external-engine/:id/cancel")]
+struct CancelPath {
+    id: EngineId,
+}
+
+struct Cancelled;
+
+impl IntoResponse for Cancelled {
+    fn into_response(self) -> Response {
+        StatusCode::NO_CONTENT.into_response()
+    }
+}
+
+#[axum_macros::debug_handler(state = AppState)]
+async fn cancel(
+    CancelPath { id }: CancelPath,
+    State(repo): State<&'static dyn EngineRegistry>,
+    State(sessions): State<&'static Ongoing<(EngineId, SessionId), Arc<Progress>>>,
+    State(metrics): State<&'static Metrics>,
+    Json(req): Json<CancelRequest>,
+) -> Result<Cancelled, Error> {
+    let (engine, _) = repo
+        .find(id, req.client_secret)
+        .await?
+        .ok_or(Error::EngineNotFound)?
+        .into_engine_and_selector();
+    let progress = sessions
+        .remove(&(engine.id, req.session_id))
+        .filter(|progress| progress.is_live())
+        .ok_or(Error::WorkNotFound)?;
+    progress.cancel();
+    metrics.cancelled.inc();
+    Ok(Cancelled)
+}
+
+#[derive(TypedPath, Deserialize)]
+#[typed_path("/api/external-engine/work")]
+struct AcquirePath;
+
+enum AcquireError {
+    Timeout,
+    ShuttingDown,
+}
+
+impl IntoResponse for AcquireError {
+    fn into_response(self) -> Response {
+        match self {
+            AcquireError::Timeout => StatusCode::NO_CONTENT.into_response(),
+            AcquireError::ShuttingDown => Error::ShuttingDown.into_response(),
+        }
+    }
+}
+
+#[axum_macros::debug_handler(state = AppState)]
+async fn acquire(
+    _: AcquirePath,
+    State(state): State<AppState>,
+    Json(req): Json<AcquireRequest>,
+) -> Result<Json<AcquireResponse>, AcquireError> {
+    let AppState {
+        hub,
+        ongoing,
+        metrics,
+        limits,
+        ref draining,
+        ..
+    } = state;
+    metrics.acquire.inc();
+    let selector = req.provider_secret.selector();
+    let instance = req.instance.as_ref();
+    if let Some(instance) = instance {
+        state.renew_lease(&selector, instance);
+    }
+    let acquired = hub.acquire(selector.clone(), |job| state.fits(job, instance));
+    let mut job = select! {
+        biased;
+        _ = draining.cancelled() => return Err(AcquireError::ShuttingDown),
+        res = timeout(limits.acquire_timeout, acquired) => res.map_err(|_: Elapsed| {
+            metrics.acquire_timeouts.inc();
+            AcquireError::Timeout
+        })?,
+    };
+    if let Some(instance) = instance {
+        state.renew_lease(&selector, instance);
+        job.acquired_by = Some(instance.clone());
+    }
+    job.progress.set_status(Status::Acquired);
+    let response = AcquireResponse {
+        id: job.id.clone(),
+        engine: job.engine.clone(),
+        work: job.work.clone(),
+    };
+    ongoing.add(job.id.clone(), job);
+    task::spawn(expire_lease(state, response.id.clone()));
+    Ok(Json(response))
+}
+
+/// Acquiring a job is a lease: if the provider does not start submitting
+/// before it expires, the job goes back to the queue for another instance.
+async fn expire_lease(state: AppState, id: JobId) {
+    sleep(state.limits.submit_timeout).await;
+    if let Some(job) = state.ongoing.remove(&id).filter(|job| job.is_valid()) {
+        requeue(&state, job);
+    }
+}
+
+/// Puts a job that a provider acquired but never submitted back into the
+/// queue, up to `max_requeues` times.
+fn requeue(state: &AppState, mut job: Job) {
+    let id = job.id.clone();
+    if let Some(instance) = job.acquired_by.take() {
+        log::warn!("{:?} did not submit {}", instance.id, id);
+        state
+            .instances
+            .remove(&(job.selector.clone(), instance.id.clone()));
+        job.avoid.push(instance.id);
+    }
+    if job.requeues >= state.limits.max_requeues {
+        log::warn!("{} not submitted after {} requeues", id, job.requeues);
+        state.metrics.failed.inc();
+        job.progress.fail();
+        return;
+    }
+    log::info!("requeuing {}", id);
+    state.metrics.requeued.inc();
+    job.requeues += 1;
+    job.progress.set_status(Status::Queued);
+    if let Err(QueueFull(_)) = state.hub.submit(job.selector.clone(), job) {
+        log::warn!("queue full, dropped requeued {}", id);
+    }
+}
+
+#[derive(TypedPath, Deserialize)]
+#[typed_path("/api/external-engine/work/:id")]
+struct SubmitPath {
+    id: JobId,
+}
+
+#[axum_macros::debug_handler(state = AppState)]
+async fn submit(
+    SubmitPath { id }: SubmitPath,
+    State(state): State<AppState>,
+    body: BodyStream,
+) -> Result<(), Error> {
+    let AppState {
+        ongoing,
+        metrics,
+        limits,
+        ..
+    } = state;
+    let work = ongoing.remove(&id).ok_or(Error::WorkNotFound)?;
+    if let Some(instance) = &work.acquired_by {
+        state.renew_lease(&work.selector, instance);
+    }
+    metrics.submit.inc();
+
+    let stream = body.map_err(io::Error::other);
+    let read = StreamReader::new(stream);
+    let lines = stream::unfold(read.lines(), |mut lines| async move {
+        lines
+            .next_line()
+            .await
+            .transpose()
+            .map(|line| (line, lines))
+    });
+    stream_analysis(work, lines, metrics, limits).await
+}
+
+/// Forwards UCI output of a provider to the client that requested the work,
+/// until the search is over, the work is cancelled, or the client is gone.
+async fn stream_analysis(
+    work: Job,
+    lines: impl Stream<Item = io::Result<String>>,
+    metrics: &'static Metrics,
+    limits: &Limits,
+) -> Result<(), Error> {
+    work.progress.set_status(Status::Streaming);
+    let (tx, rx) = mpsc::channel(1);
+    let _: Result<(), _> = work.tx.send(rx);
+
+    pin!(lines);
+    let mut emit = Emit::new(&work.work);
+    let cap = sleep(limits.max_infinite);
+    pin!(cap);
+    let mut capped = false;
+
+    while let Some(line) = select! {
+        biased;
+        _ = work.progress.cancelled() => {
+            log::info!("work cancelled");
+            return Err(Error::Cancelled);
+        },
+        _ = &mut cap, if work.work.is_infinite() => {
+            log::info!("infinite analysis reached time limit");
+            capped = true;
+            None
+        },
+        maybe_line = lines.next() => match maybe_line {
+            Some(Ok(line)) => Some(line),
+            Some(Err(err)) => {
+                log::warn!("provider gone away: {}", err);
+                work.progress.fail();
+                send_final(tx, Err(Error::ProviderDisconnected), metrics);
+                return Err(err.into());
+            }
+            None => None,
+        },
+        _ = tx.closed() => {
+            log::info!("requester gone away");
+            None
+        },
+    } {
+        metrics.provider_lines.inc();
+        if let Some(uci) = UciOut::from_line(&line, limits.uci_parsing)? {
+            if let UciOut::Info {
+                multipv, ref extra, ..
+            } = uci
+            {
+                if multipv.is_some_and(|multipv| multipv > work.work.multi_pv()) {
+                    // Not requested, and possibly more than the broker allows.
+                    log::debug!("skipped unrequested multipv {multipv:?}");
+                    continue;
+                }
+                if !extra.is_empty() {
+                    log::debug!("skipped unknown info tokens: {extra:?}");
+                    metrics.lenient_lines.inc();
+                }
+            }
+            emit.update(&uci, &work.pos, limits.max_pv_moves);
+
+            if matches!(uci, UciOut::Bestmove { .. }) {
+                work.progress.set_emit(emit.clone());
+                send_final(tx, Ok(emit), metrics);
+                return Ok(());
+            }
+
+            if emit.should_emit() {
+                work.progress.set_emit(emit.clone());
+                if tx.send(Ok(emit.clone())).await.is_err() {
+                    log::info!("requester suddenly gone away");
+                    break;
+                }
+                emit.emitted();
+                metrics.emitted_lines.inc();
+            }
+        }
+    }
+
+    // Ending the submission tells the provider to stop the search.
+    if capped {
+        emit.finish();
+        work.progress.set_emit(emit.clone());
+        send_final(tx, Ok(emit), metrics);
+    }
+    Ok(())
+}
+
+/// Delivers the final emit or error in the background, so that the provider
+/// is not held up by a slow client.
+fn send_final(
+    tx: mpsc::Sender<Result<Emit, Error>>,
+    emit: Result<Emit, Error>,
+    metrics: &'static Metrics,
+) {
+    task::spawn(async move {
+        let is_emit = emit.is_ok();
+        if tx.send(emit).await.is_ok() && is_emit {
+            metrics.emitted_lines.inc();
+        }
+    });
+}
+
+/// Largest message accepted on a socket.
+const MAX_SOCKET_MESSAGE: usize = 64 * 1024;
+
+/// Lines buffered for each job on a provider socket, while the client is
+/// catching up.
+const SOCKET_LINE_BUFFER: usize = 16;
+
+/// Completes a WebSocket handshake, and then serves the connection on a
+/// separate task.
+#[allow(clippy::result_large_err)]
+fn upgrade<F, Fut>(
+    ws: Result<WebSocketUpgrade, WebSocketUpgradeRejection>,
+    serve: F,
+) -> Result<Response, Error>
+where
+    F: FnOnce(WebSocket) -> Fut + Send + 'static,
+    Fut: Future<Output = Result<(), axum::Error>> + Send + 'static,
+{
+    let ws = ws.map_err(|_| Error::UpgradeRequired)?;
+    Ok(ws
+        .max_message_size(MAX_SOCKET_MESSAGE)
+        .on_upgrade(|socket| async move {
+            if let Err(err) = serve(socket).await {
+                log::warn!("socket: {}", err);
+            }
+        }))
+}
+
+/// What became of a message received on a socket.
+enum Incoming {
+    Text(String),
+    /// A control message, that was already taken care of.
+    Handled,
+    Closed,
+}
+
+/// Rejects binary messages, leaving only text messages for the caller.
+/// Pings and closes are answered by the WebSocket implementation.
+async fn handle(
+    socket: &mut WebSocket,
+    message: Option<Result<Message, axum::Error>>,
+) -> Result<Incoming, axum::Error> {
+    Ok(match message {
+        Some(Ok(Message::Text(text))) => Incoming::Text(text),
+        Some(Ok(Message::Ping(_) | Message::Pong(_))) => Incoming::Handled,
+        Some(Ok(Message::Binary(_))) => {
+            close(socket, close_code::UNSUPPORTED, "expected text").await?;
+            Incoming::Closed
+        }
+        Some(Ok(Message::Close(_))) | None => Incoming::Closed,
+        Some(Err(err)) => {
+            log::debug!("socket error: {}", err);
+            Incoming::Closed
+        }
+    })
+}
+
+/// Waits for the first text message, which has to be valid JSON. Closes the
+/// connection and returns `None` otherwise.
+async fn hello<H: DeserializeOwned>(
+    socket: &mut WebSocket,
+    within: Duration,
+) -> Result<Option<H>, axum::Error> {
+    loop {
+        let Ok(message) = timeout(within, socket.recv()).await else {
+            close(socket, close_code::POLICY, "expected hello").await?;
+            return Ok(None);
+        };
+        match handle(socket, message).await? {
+            Incoming::Text(text) => {
+                return match serde_json::from_str(&text) {
+                    Ok(hello) => Ok(Some(hello)),
+                    Err(err) => {
+                        close(socket, close_code::INVALID, &err.to_string()).await?;
+                        Ok(None)
+                    }
+                }
+            }
+            Incoming::Handled => continue,
+            Incoming::Closed => return Ok(None),
+        }
+    }
+}
+
+async fn close(socket: &mut WebSocket, code: u16, reason: &str) -> Result<(), axum::Error> {
+    // The reason has to fit into a control frame.
+    let mut end = reason.len().min(123);
+    while !reason.is_char_boundary(end) {
+        end -= 1;
+    }
+    socket
+        .send(Message::Close(Some(CloseFrame {
+            code,
+            reason: reason[..end].to_owned().into(),
+        })))
+        .await
+}
+
+#[derive(TypedPath, Deserialize)]
+#[typed_path("/api/external-engine/work/socket")]
+struct ProviderSocketPath;
+
+#[axum_macros::debug_handler(state = AppState)]
+async fn provider_socket(
+    _: ProviderSocketPath,
+    State(state): State<AppState>,
+    ws: Result<WebSocketUpgrade, WebSocketUpgradeRejection>,
+) -> Result<Response, Error> {
+    if state.draining.is_cancelled() {
+        return Err(Error::ShuttingDown);
+    }
+    upgrade(ws, move |socket| {
+        state.metrics.sockets.inc();
+        serve_provider(state, socket)
+    })
+}
+
+/// Pushes work to a provider as long as it has capacity, and dispatches the
+/// UCI lines it sends back to the respective jobs.
+async fn serve_provider(state: AppState, mut socket: WebSocket) -> Result<(), axum::Error> {
+    let Some(hello) = hello::<SocketHello>(&mut socket, state.limits.acquire_timeout).await? else {
+        return Ok(());
+    };
+    let selector = hello.provider_secret.selector();
+    let instance = hello.instance;
+    let concurrency = hello.concurrency.get() as usize;
+
+    let (done_tx, mut done) = mpsc::unbounded_channel();
+    let mut jobs: HashMap<JobId, mpsc::Sender<String>> = HashMap::new();
+
+    loop {
+        if let Some(instance) = &instance {
+            state.renew_lease(&selector, instance);
+        }
+        let draining = state.draining.is_cancelled();
+        if draining && jobs.is_empty() {
+            return close(&mut socket, close_code::AWAY, "shutting down").await;
+        }
+        select! {
+            biased;
+            Some(id) = done.recv() => {
+                jobs.remove(&id);
+                socket.send(SocketMessage::Stop { id }.to_message()).await?;
+            }
+            message = socket.recv() => match handle(&mut socket, message).await? {
+                Incoming::Text(text) => match serde_json::from_str::<SocketLine>(&text) {
+                    Ok(SocketLine { id, line }) => {
+                        if let Some(lines) = jobs.get(&id) {
+                            forward_line(lines, line, state.metrics);
+                        }
+                    }
+                    Err(err) => return close(&mut socket, close_code::INVALID, &err.to_string()).await,
+                },
+                Incoming::Handled => (),
+                Incoming::Closed => return Ok(()),
+            },
+            _ = state.draining.cancelled(), if !draining => (),
+            mut job = state.hub.acquire(selector.clone(), |job| state.fits(job, instance.as_ref())),
+                if !draining && jobs.len() < concurrency =>
+            {
+                state.metrics.socket_jobs.inc();
+                if let Some(instance) = &instance {
+                    job.acquired_by = Some(instance.clone());
+                }
+                job.progress.set_status(Status::Acquired);
+                let id = job.id.clone();
+                let work = SocketMessage::Work(Box::new(AcquireResponse {
+                    id: id.clone(),
+                    engine: job.engine.clone(),
+                    work: job.work.clone(),
+                }));
+                if let Err(err) = socket.send(work.to_message()).await {
+                    requeue(&state, job);
+                    return Err(err);
+                }
+                let (lines_tx, lines) = mpsc::channel(SOCKET_LINE_BUFFER);
+                jobs.insert(id.clone(), lines_tx);
+                task::spawn(serve_socket_job(state.clone(), job, lines, done_tx.clone()));
+            }
+        }
+    }
+}
+
+/// Streams the lines a provider sends on its socket for a job. Work that the
+/// provider never started on goes back to the queue when the socket closes.
+async fn serve_socket_job(
+    state: AppState,
+    mut job: Job,
+    mut lines: mpsc::Receiver<String>,
+    done: mpsc::UnboundedSender<JobId>,
+) {
+    let id = job.id.clone();
+    let first = select! {
+        first = lines.recv() => first,
+        _ = job.progress.cancelled() => None,
+        _ = job.tx.closed() => None,
+    };
+    match first {
+        Some(first) => {
+            // Lines only end before the best move if the socket is gone.
+            let lines = stream::once(future::ready(first))
+                .chain(ReceiverStream::new(lines))
+                .map(Ok)
+                .chain(stream::once(future::ready(Err(io::Error::new(
+                    io::ErrorKind::ConnectionAborted,
+                    "provider socket closed",
+                )))));
+            if let Err(err) = stream_analysis(job, lines, state.metrics, state.limits).await {
+                log::warn!("{} on provider socket: {}", id, err);
+            }
+        }
+        None if job.is_valid() => requeue(&state, job),
+        None => (),
+    }
+    let _: Result<(), _> = done.send(id);
+}
+
+/// Hands a line to the job it belongs to, without waiting for a slow client,
+/// which would hold up all other jobs on the socket. Intermediate lines are
+/// dropped when the buffer of the job is full, but the best move never is.
+fn forward_line(lines: &mpsc::Sender<String>, line: String, metrics: &'static Metrics) {
+    match lines.try_send(line) {
+        Ok(()) | Err(TrySendError::Closed(_)) => (),
+        Err(TrySendError::Full(line)) if line.split_whitespace().next() == Some("bestmove") => {
+            let lines = lines.clone();
+            task::spawn(async move {
+                let _: Result<(), _> = lines.send(line).await;
+            });
+        }
+        Err(TrySendError::Full(_)) => metrics.socket_lines_dropped.inc(),
+    }
+}
+
+#[derive(TypedPath, Deserialize)]
+#[typed_path("/api/external-engine/:id/socket")]
+struct ClientSocketPath {
+    id: EngineId,
+}
+
+#[axum_macros::debug_handler(state = AppState)]
+async fn client_socket(
+    ClientSocketPath { id }: ClientSocketPath,
+    State(state): State<AppState>,
+    ws: Result<WebSocketUpgrade, WebSocketUpgradeRejection>,
+) -> Result<Response, Error> {
+    if state.draining.is_cancelled() {
+        return Err(Error::ShuttingDown);
+    }
+    upgrade(ws, move |socket| serve_client(state, id, socket))
+}
+
+/// Analyses the latest work a client sent, replacing whatever was analysed
+/// before.
+async fn serve_client(
+    state: AppState,
+    engine_id: EngineId,
+    mut socket: WebSocket,
+) -> Result<(), axum::Error> {
+    let Some(hello) = hello::<ClientHello>(&mut socket, state.limits.provider_timeout).await?
+    else {
+        return Ok(());
+    };
+    let (engine, provider_selector) = match state.repo.find(engine_id, hello.client_secret).await {
+        Ok(Some(engine)) => engine.into_engine_and_selector(),
+        Ok(None) => {
+            let reason = Error::EngineNotFound.to_string();
+            return close(&mut socket, close_code::POLICY, &reason).await;
+        }
+        Err(err) => {
+            let reason = Error::from(err).to_string();
+            return close(&mut socket, close_code::ERROR, &reason).await;
+        }
+    };
+
+    let mut starting: Option<BoxFuture<'static, Result<Analysis, Error>>> = None;
+    let mut current: Option<Analysis> = None;
+
+    loop {
+        let idle = starting.is_none() && current.is_none();
+        select! {
+            message = socket.recv() => match handle(&mut socket, message).await? {
+                Incoming::Text(text) => match serde_json::from_str::<ClientWork>(&text) {
+                    Ok(ClientWork { work }) => {
+                        current = None;
+                        starting = Some(if state.draining.is_cancelled() {
+                            future::ready(Err(Error::ShuttingDown)).boxed()
+                        } else {
+                            start_analysis(state.clone(), engine.clone(), provider_selector.clone(), work).boxed()
+                        });
+                    }
+                    Err(err) => return close(&mut socket, close_code::INVALID, &err.to_string()).await,
+                },
+                Incoming::Handled => (),
+                Incoming::Closed => return Ok(()),
+            },
+            res = async { starting.as_mut().expect("starting").await }, if starting.is_some() => {
+                starting = None;
+                match res {
+                    Ok(analysis) => current = Some(analysis),
+                    Err(err) => {
+                        let error = ClientMessage::Error { error: err.to_string() };
+                        socket.send(error.to_message()).await?;
+                    }
+                }
+            }
+            emit = async { current.as_mut().expect("current").emits.next().await }, if current.is_some() => {
+                let id = current.as_ref().expect("current").id.clone();
+                match emit {
+                    Some(Ok(emit)) => socket.send(ClientMessage::Emit { id, emit }.to_message()).await?,
+                    Some(Err(err)) => {
+                        current = None;
+                        let error = ClientMessage::Error { error: err.to_string() };
+                        socket.send(error.to_message()).await?;
+                    }
+                    None => {
+                        current = None;
+                        socket.send(ClientMessage::Done { id }.to_message()).await?;
+                    }
+                }
+            }
+            _ = state.draining.cancelled(), if idle => {
+                return close(&mut socket, close_code::AWAY, "shutting down").await;
+            }
+        }
+    }
+}
+
+#[derive(TypedPath, Deserialize)]
+#[typed_path("/api/external-engine/work/:id/status")]
+struct StatusPath {
+    id: JobId,
+}
+
+#[axum_macros::debug_handler(state = AppState)]
+async fn status(
+    StatusPath { id }: StatusPath,
+    State(jobs): State<&'static Ongoing<JobId, Arc<Progress>>>,
+) -> Result<Json<Snapshot>, Error> {
+    let progress = jobs
+        .get(&id)
+        .filter(|progress| progress.is_valid())
+        .ok_or(Error::WorkNotFound)?;
+    Ok(Json(progress.snapshot()))
+}
+
+#[derive(TypedPath, Deserialize)]
+#[typed_path("/metrics")]
+struct MetricsPath;
+
+#[axum_macros::debug_handler(state = AppState)]
+async fn scrape(_: MetricsPath, State(state): State<AppState>) -> String {
+    metrics::render(&state)
+}
+
+#[derive(TypedPath, Deserialize)]
+#[typed_path("/health")]
+struct HealthPath;
+
+#[axum_macros::debug_handler(state = AppState)]
+async fn health(_: HealthPath) -> &'static str {
+    "ok"
+}
+
+#[derive(TypedPath, Deserialize)]
+#[typed_path("/ready")]
+struct ReadyPath;
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Readiness {
+    ready: bool,
+    draining: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    registry_error: Option<String>,
+    hub: ShardStats,
+    ongoing: ShardStats,
+    sessions: ShardStats,
+    progress: ShardStats,
+    instances: ShardStats,
+}
+
+#[axum_macros::debug_handler(state = AppState)]
+async fn ready(_: ReadyPath, State(state): State<AppState>) -> (StatusCode, Json<Readiness>) {
+    let registry_error = match timeout(Duration::from_secs(5), state.repo.ping()).await {
+        Ok(Ok(())) => None,
+        Ok(Err(err)) => Some(err.to_string()),
+        Err(_) => Some("ping timed out".to_owned()),
+    };
+    let hub = state.hub.stats();
+    let ongoing = state.ongoing.stats();
+    let sessions = state.sessions.stats();
+    let progress = state.progress.stats();
+    let instances = state.instances.stats();
+    let draining = state.draining.is_cancelled();
+    let ready = !draining
+        && registry_error.is_none()
+        && [&hub, &ongoing, &sessions, &progress, &instances]
+            .iter()
+            .all(|stats| stats.is_collecting(state.limits.max_collector_pause()));
+    (
+        if ready {
+            StatusCode::OK
+        } else {
+            StatusCode::SERVICE_UNAVAILABLE
+        },
+        Json(Readiness {
+            ready,
+            draining,
+            registry_error,
+            hub,
+            ongoing,
+            sessions,
+            progress,
+            instances,
+        }),
+    )
+}
@@ -0,0 +1,102 @@
+use std::{cmp::max, time::Duration};
+
+use thiserror::Error;
+
+use crate::{model::MultiPv, rate_limit::RateLimit, uci::Parsing};
+
+/// Tunable timeouts and limits of the broker.
+#[derive(Debug, Clone)]
+pub struct Limits {
+    /// How long a client waits for a provider to pick up its work.
+    pub provider_timeout: Duration,
+    /// How long a provider long-polls for work.
+    pub acquire_timeout: Duration,
+    /// How long a provider may take from acquiring work to submitting it,
+    /// before the work is handed to another instance.
+    pub submit_timeout: Duration,
+    /// How often work that was not submitted in time is requeued, before
+    /// giving up.
+    pub max_requeues: u32,
+    /// How long a provider instance is considered alive after it last
+    /// acquired or submitted work.
+    pub instance_timeout: Duration,
+    /// Number of shards of the work queue.
+    pub hub_shards: usize,
+    /// Maximum number of queued jobs per provider.
+    pub max_queued: usize,
+    /// Pause between collecting two shards of the work queue.
+    pub hub_gc_interval: Duration,
+    /// Pause between collecting two shards of ongoing work.
+    pub ongoing_gc_interval: Duration,
+    /// Maximum number of moves from the initial position.
+    pub max_moves: usize,
+    /// Maximum number of moves in each emitted principal variation.
+    pub max_pv_moves: usize,
+    /// How long infinite analysis may run, before the broker stops it.
+    pub max_infinite: Duration,
+    /// Maximum number of principal variations per job.
+    pub max_multi_pv: MultiPv,
+    /// How to treat unknown tokens in UCI output of providers.
+    pub uci_parsing: Parsing,
+    /// Analyse requests per engine.
+    pub engine_rate: RateLimit,
+    /// Analyse requests per user, across all of their engines.
+    pub user_rate: RateLimit,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            provider_timeout: Duration::from_secs(15),
+            acquire_timeout: Duration::from_secs(10),
+            submit_timeout: Duration::from_secs(4),
+            max_requeues: 2,
+            instance_timeout: Duration::from_secs(60),
+            hub_shards: 64,
+            max_queued: 1024,
+            hub_gc_interval: Duration::from_secs(13),
+            ongoing_gc_interval: Duration::from_secs(7),
+            max_moves: 600,
+            max_pv_moves: 30,
+            max_infinite: Duration::from_secs(5 * 60),
+            max_multi_pv: MultiPv::try_from(5).expect("default multi pv"),
+            uci_parsing: Parsing::Strict,
+            engine_rate: RateLimit {
+                burst: 60,
+                per_minute: 120,
+            },
+            user_rate: RateLimit {
+                burst: 120,
+                per_minute: 240,
+            },
+        }
+    }
+}
+
+/// Work that is acquired but never submitted would not fail with its own
+/// error, because the client gives up first.
+#[derive(Error, Debug)]
+#[error("submit timeout times (max requeues + 1) must be less than provider timeout")]
+pub struct InconsistentLimits;
+
+impl Limits {
+    /// Checks that the limits are consistent with each other.
+    pub fn validate(&self) -> Result<(), InconsistentLimits> {
+        match self
+            .submit_timeout
+            .checked_mul(self.max_requeues.saturating_add(1))
+        {
+            Some(leases) if leases < self.provider_timeout => Ok(()),
+            _ => Err(InconsistentLimits),
+        }
+    }
+
+    /// A garbage collector that has not made progress for this long is
+    /// considered stuck or gone.
+    pub fn max_collector_pause(&self) -> Duration {
+        max(
+            Duration::from_secs(60),
+            4 * max(self.hub_gc_interval, self.ongoing_gc_interval),
+        )
+    }
+}
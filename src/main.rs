@@ -1,55 +1,29 @@
-use std::{convert::Infallible, io, net::SocketAddr, path::PathBuf, time::Duration};
-
-use axum::{
-    extract::{BodyStream, FromRef, Json, State},
-    http::StatusCode,
-    response::{IntoResponse, Response},
-    Router,
-};
-use axum_extra::{
-    json_lines,
-    json_lines::JsonLines,
-    routing::{RouterExt, TypedPath},
+use std::{net::SocketAddr, path::PathBuf, time::Duration};
+
+use axum_server::{tls_rustls::RustlsConfig, Handle};
+use clap::{builder::PathBufValueParser, error::ErrorKind, value_parser, CommandFactory, Parser};
+use futures::future;
+use lila_engine::{
+    limits::{InconsistentLimits, Limits},
+    metrics_router,
+    model::MultiPv,
+    rate_limit::RateLimit,
+    repo::{EngineRegistry, MemoryRepo, MongoRepo},
+    router,
+    uci::Parsing,
+    AppState,
 };
-use axum_server::tls_rustls::RustlsConfig;
-use clap::{builder::PathBufValueParser, Parser};
-use futures::Stream;
-use futures_util::stream::{StreamExt, TryStreamExt};
-use serde::Deserialize;
-use shakmaty::variant::VariantPosition;
-use thiserror::Error;
 use tokio::{
-    io::AsyncBufReadExt,
-    select,
-    sync::{
-        mpsc,
-        oneshot::{self, error::RecvError},
-    },
+    signal::unix::{signal, SignalKind},
     task,
-    time::{error::Elapsed, timeout},
+    time::{sleep, timeout_at, Instant},
 };
-use tokio_stream::wrappers::ReceiverStream;
-use tokio_util::io::StreamReader;
-use tower_http::{cors::CorsLayer, trace::TraceLayer};
+use tokio_util::sync::CancellationToken;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
-use crate::{
-    api::{AcquireRequest, AcquireResponse, AnalyseRequest, InvalidWorkError, Work},
-    emit::Emit,
-    hub::{Hub, IsValid},
-    model::{Engine, EngineId, JobId, ProviderSelector},
-    ongoing::Ongoing,
-    repo::Repo,
-    uci::UciOut,
-};
-
-mod api;
-mod emit;
-mod hub;
-mod model;
-mod ongoing;
-mod repo;
-mod uci;
+/// How often to check whether acquired jobs have started submitting, while
+/// shutting down.
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(100);
 
 #[derive(Parser)]
 struct Opt {
@@ -59,84 +33,191 @@ struct Opt {
     /// Binding address for HTTPS.
     #[arg(long)]
     pub bind_tls: Option<SocketAddr>,
+    /// Binding address for Prometheus metrics.
+    #[arg(long)]
+    pub bind_metrics: Option<SocketAddr>,
     /// Database.
     #[arg(long, default_value = "mongodb://localhost")]
     pub mongodb: String,
+    /// Load external engines from a JSON file instead of the database.
+    #[arg(long, value_parser = PathBufValueParser::new())]
+    pub engines: Option<PathBuf>,
     /// Certificate file for HTTPS server.
     #[arg(long, value_parser = PathBufValueParser::new())]
     pub cert_pem: Option<PathBuf>,
     /// Private key for HTTPS server.
     #[arg(long, value_parser = PathBufValueParser::new())]
     pub key_pem: Option<PathBuf>,
+    /// Seconds to wait for ongoing analysis to finish after SIGTERM.
+    #[arg(long, env = "LILA_ENGINE_SHUTDOWN_TIMEOUT", default_value = "30")]
+    pub shutdown_timeout: u64,
+    /// Seconds a client waits for a provider to pick up its work.
+    #[arg(
+        long,
+        env = "LILA_ENGINE_PROVIDER_TIMEOUT",
+        default_value_t = Limits::default().provider_timeout.as_secs()
+    )]
+    pub provider_timeout: u64,
+    /// Seconds a provider long-polls for work.
+    #[arg(
+        long,
+        env = "LILA_ENGINE_ACQUIRE_TIMEOUT",
+        default_value_t = Limits::default().acquire_timeout.as_secs()
+    )]
+    pub acquire_timeout: u64,
+    /// Seconds a provider may take from acquiring work to submitting it.
+    #[arg(
+        long,
+        env = "LILA_ENGINE_SUBMIT_TIMEOUT",
+        default_value_t = Limits::default().submit_timeout.as_secs()
+    )]
+    pub submit_timeout: u64,
+    /// How often work that was not submitted in time is requeued.
+    #[arg(
+        long,
+        env = "LILA_ENGINE_MAX_REQUEUES",
+        default_value_t = Limits::default().max_requeues
+    )]
+    pub max_requeues: u32,
+    /// Seconds a provider instance is considered alive after it was last
+    /// heard from.
+    #[arg(
+        long,
+        env = "LILA_ENGINE_INSTANCE_TIMEOUT",
+        default_value_t = Limits::default().instance_timeout.as_secs()
+    )]
+    pub instance_timeout: u64,
+    /// Number of shards of the work queue.
+    #[arg(
+        long,
+        env = "LILA_ENGINE_HUB_SHARDS",
+        default_value_t = Limits::default().hub_shards
+    )]
+    pub hub_shards: usize,
+    /// Maximum number of queued jobs per provider.
+    #[arg(
+        long,
+        env = "LILA_ENGINE_MAX_QUEUED",
+        default_value_t = Limits::default().max_queued
+    )]
+    pub max_queued: usize,
+    /// Seconds between collecting two shards of the work queue.
+    #[arg(
+        long,
+        env = "LILA_ENGINE_HUB_GC_INTERVAL",
+        default_value_t = Limits::default().hub_gc_interval.as_secs()
+    )]
+    pub hub_gc_interval: u64,
+    /// Seconds between collecting two shards of ongoing work.
+    #[arg(
+        long,
+        env = "LILA_ENGINE_ONGOING_GC_INTERVAL",
+        default_value_t = Limits::default().ongoing_gc_interval.as_secs()
+    )]
+    pub ongoing_gc_interval: u64,
+    /// Maximum number of moves from the initial position.
+    #[arg(
+        long,
+        env = "LILA_ENGINE_MAX_MOVES",
+        default_value_t = Limits::default().max_moves
+    )]
+    pub max_moves: usize,
+    /// Maximum number of moves in each emitted principal variation.
+    #[arg(
+        long,
+        env = "LILA_ENGINE_MAX_PV_MOVES",
+        default_value_t = Limits::default().max_pv_moves
+    )]
+    pub max_pv_moves: usize,
+    /// Seconds that infinite analysis may run, before the broker stops it.
+    #[arg(
+        long,
+        env = "LILA_ENGINE_MAX_INFINITE",
+        default_value_t = Limits::default().max_infinite.as_secs()
+    )]
+    pub max_infinite: u64,
+    /// Maximum number of principal variations per job.
+    #[arg(
+        long,
+        env = "LILA_ENGINE_MAX_MULTI_PV",
+        default_value_t = Limits::default().max_multi_pv.into(),
+        value_parser = value_parser!(u32).range(1..=i64::from(u32::from(MultiPv::MAX)))
+    )]
+    pub max_multi_pv: u32,
+    /// How to treat unknown tokens in info lines of providers.
+    #[arg(
+        long,
+        value_enum,
+        env = "LILA_ENGINE_UCI_PARSING",
+        default_value_t = Limits::default().uci_parsing
+    )]
+    pub uci_parsing: Parsing,
+    /// Analyse requests per engine in quick succession (0 for unlimited).
+    #[arg(
+        long,
+        env = "LILA_ENGINE_ENGINE_RATE_BURST",
+        default_value_t = Limits::default().engine_rate.burst
+    )]
+    pub engine_rate_burst: u32,
+    /// Sustained analyse requests per engine and minute.
+    #[arg(
+        long,
+        env = "LILA_ENGINE_ENGINE_RATE_PER_MINUTE",
+        default_value_t = Limits::default().engine_rate.per_minute
+    )]
+    pub engine_rate_per_minute: u32,
+    /// Analyse requests per user in quick succession (0 for unlimited).
+    #[arg(
+        long,
+        env = "LILA_ENGINE_USER_RATE_BURST",
+        default_value_t = Limits::default().user_rate.burst
+    )]
+    pub user_rate_burst: u32,
+    /// Sustained analyse requests per user and minute.
+    #[arg(
+        long,
+        env = "LILA_ENGINE_USER_RATE_PER_MINUTE",
+        default_value_t = Limits::default().user_rate.per_minute
+    )]
+    pub user_rate_per_minute: u32,
 }
 
-struct Job {
-    tx: oneshot::Sender<mpsc::Receiver<Emit>>,
-    pos: VariantPosition,
-    engine: Engine,
-    work: Work,
-}
-
-impl IsValid for Job {
-    fn is_valid(&self) -> bool {
-        !self.tx.is_closed()
-    }
-}
-
-#[derive(Clone)]
-struct AppState {
-    repo: &'static Repo,
-    hub: &'static Hub<ProviderSelector, Job>,
-    ongoing: &'static Ongoing<JobId, Job>,
-}
-
-impl FromRef<AppState> for &'static Repo {
-    fn from_ref(state: &AppState) -> &'static Repo {
-        state.repo
-    }
-}
-
-impl FromRef<AppState> for &'static Hub<ProviderSelector, Job> {
-    fn from_ref(state: &AppState) -> &'static Hub<ProviderSelector, Job> {
-        state.hub
+impl Opt {
+    fn limits(&self) -> Result<Limits, InconsistentLimits> {
+        let limits = Limits {
+            provider_timeout: Duration::from_secs(self.provider_timeout),
+            acquire_timeout: Duration::from_secs(self.acquire_timeout),
+            submit_timeout: Duration::from_secs(self.submit_timeout),
+            max_requeues: self.max_requeues,
+            instance_timeout: Duration::from_secs(self.instance_timeout),
+            hub_shards: self.hub_shards,
+            max_queued: self.max_queued,
+            hub_gc_interval: Duration::from_secs(self.hub_gc_interval),
+            ongoing_gc_interval: Duration::from_secs(self.ongoing_gc_interval),
+            max_moves: self.max_moves,
+            max_pv_moves: self.max_pv_moves,
+            max_infinite: Duration::from_secs(self.max_infinite),
+            max_multi_pv: MultiPv::try_from(self.max_multi_pv).expect("max multi pv"),
+            uci_parsing: self.uci_parsing,
+            engine_rate: RateLimit {
+                burst: self.engine_rate_burst,
+                per_minute: self.engine_rate_per_minute,
+            },
+            user_rate: RateLimit {
+                burst: self.user_rate_burst,
+                per_minute: self.user_rate_per_minute,
+            },
+        };
+        limits.validate()?;
+        Ok(limits)
     }
 }
 
-impl FromRef<AppState> for &'static Ongoing<JobId, Job> {
-    fn from_ref(state: &AppState) -> &'static Ongoing<JobId, Job> {
-        state.ongoing
-    }
-}
-
-#[derive(Error, Debug)]
-enum Error {
-    #[error("mongodb error: {0}")]
-    MongoDb(#[from] mongodb::error::Error),
-    #[error("engine not found or invalid clientSecret")]
-    EngineNotFound,
-    #[error("work not found or cancelled or expired")]
-    WorkNotFound,
-    #[error("i/o error: {0}")]
-    Io(#[from] io::Error),
-    #[error("uci protocol error: {0}")]
-    Protocol(#[from] uci::ProtocolError),
-    #[error("invalid work: {0}")]
-    InvalidWork(#[from] InvalidWorkError),
-    #[error("recv error: {0}")]
-    RecvError(#[from] RecvError),
-    #[error("provider did not pick up work")]
-    ProviderTimeout,
-}
-
-impl IntoResponse for Error {
-    fn into_response(self) -> Response {
-        let status = match self {
-            Error::MongoDb(_) | Error::RecvError(_) => StatusCode::INTERNAL_SERVER_ERROR,
-            Error::Io(_) | Error::Protocol(_) | Error::InvalidWork(_) => StatusCode::BAD_REQUEST,
-            Error::EngineNotFound | Error::WorkNotFound => StatusCode::NOT_FOUND,
-            Error::ProviderTimeout => StatusCode::SERVICE_UNAVAILABLE,
-        };
-        (status, self.to_string()).into_response()
+async fn shutdown_signal() {
+    let mut terminate = signal(SignalKind::terminate()).expect("sigterm handler");
+    tokio::select! {
+        _ = terminate.recv() => (),
+        res = tokio::signal::ctrl_c() => res.expect("ctrl-c handler"),
     }
 }
 
@@ -151,27 +232,51 @@ async fn main() {
         .init();
 
     let opt = Opt::parse();
-
-    let state = AppState {
-        repo: Box::leak(Box::new(Repo::new(&opt.mongodb).await)),
-        hub: Box::leak(Box::new(Hub::default())),
-        ongoing: Box::leak(Box::new(Ongoing::default())),
+    let limits = opt.limits().unwrap_or_else(|err| {
+        Opt::command()
+            .error(ErrorKind::ArgumentConflict, err)
+            .exit()
+    });
+
+    let repo: &'static dyn EngineRegistry = match opt.engines {
+        Some(ref path) => Box::leak(Box::new(MemoryRepo::from_file(path).expect("engines file"))),
+        None => Box::leak(Box::new(MongoRepo::new(&opt.mongodb).await)),
     };
 
-    task::spawn(state.hub.garbage_collect());
-    task::spawn(state.ongoing.garbage_collect());
+    let state = AppState::new(repo, limits.clone());
+
+    task::spawn(state.hub.garbage_collect(limits.hub_gc_interval));
+    task::spawn(state.ongoing.garbage_collect(limits.ongoing_gc_interval));
+    task::spawn(state.sessions.garbage_collect(limits.ongoing_gc_interval));
+    task::spawn(state.progress.garbage_collect(limits.ongoing_gc_interval));
+    task::spawn(state.instances.garbage_collect(limits.ongoing_gc_interval));
+    task::spawn(
+        state
+            .engine_rate
+            .garbage_collect(limits.ongoing_gc_interval),
+    );
+    task::spawn(state.user_rate.garbage_collect(limits.ongoing_gc_interval));
+
+    if let Some(bind) = opt.bind_metrics {
+        let metrics_app = metrics_router(state.clone());
+        task::spawn(async move {
+            axum::Server::bind(&bind)
+                .serve(metrics_app.into_make_service())
+                .await
+                .expect("bind metrics");
+        });
+    }
 
-    let app = Router::new()
-        .typed_post(analyse)
-        .typed_post(acquire)
-        .typed_post(submit)
-        .layer(CorsLayer::permissive().max_age(Duration::from_secs(60 * 60 * 24)))
-        .layer(TraceLayer::new_for_http())
-        .with_state(state);
+    let app = router(state.clone());
+    let shutdown_timeout = Duration::from_secs(opt.shutdown_timeout);
+    let tls_handle = Handle::new();
+    let stop = CancellationToken::new();
+    let mut servers = Vec::new();
 
     if let Some(bind) = opt.bind_tls {
         let tls_app = app.clone();
-        task::spawn(async move {
+        let tls_handle = tls_handle.clone();
+        servers.push(task::spawn(async move {
             axum_server::bind_rustls(
                 bind,
                 RustlsConfig::from_pem_file(
@@ -181,132 +286,40 @@ async fn main() {
                 .await
                 .expect("tls config"),
             )
+            .handle(tls_handle)
             .serve(tls_app.into_make_service())
             .await
             .expect("bind tls");
-        });
+        }));
     }
 
     if let Some(bind) = opt.bind {
-        axum::Server::bind(&bind)
-            .serve(app.into_make_service())
-            .await
-            .expect("bind");
+        let stop = stop.clone();
+        servers.push(task::spawn(async move {
+            axum::Server::bind(&bind)
+                .serve(app.into_make_service())
+                .with_graceful_shutdown(async move { stop.cancelled().await })
+                .await
+                .expect("bind");
+        }));
     }
-}
-
-#[derive(TypedPath, Deserialize)]
-#[typed_path("/api/external-engine/:id/analyse")]
-struct AnalysePath {
-    id: EngineId,
-}
-
-#[axum_macros::debug_handler(state = AppState)]
-async fn analyse(
-    AnalysePath { id }: AnalysePath,
-    State(hub): State<&'static Hub<ProviderSelector, Job>>,
-    State(repo): State<&'static Repo>,
-    Json(req): Json<AnalyseRequest>,
-) -> Result<JsonLines<impl Stream<Item = Result<Emit, Infallible>>, json_lines::AsResponse>, Error>
-{
-    let (engine, provider_selector) = repo
-        .find(id, req.client_secret)
-        .await?
-        .ok_or(Error::EngineNotFound)?
-        .into_engine_and_selector();
-    let (work, pos) = req.work.sanitize(&engine)?;
-    let (tx, rx) = oneshot::channel();
-    hub.submit(
-        provider_selector,
-        Job {
-            tx,
-            engine,
-            work,
-            pos,
-        },
-    );
-    let rx = timeout(Duration::from_secs(15), rx)
-        .await
-        .map_err(|_: Elapsed| Error::ProviderTimeout)??;
-    Ok(JsonLines::new(
-        ReceiverStream::new(rx).map(Ok::<_, Infallible>),
-    ))
-}
 
-#[derive(TypedPath, Deserialize)]
-#[typed_path("/api/external-engine/work")]
-struct AcquirePath;
+    shutdown_signal().await;
+    log::info!("shutting down, draining for up to {shutdown_timeout:?}");
+    state.drain();
+    let deadline = Instant::now() + shutdown_timeout;
 
-struct AcquireTimeout;
-
-impl IntoResponse for AcquireTimeout {
-    fn into_response(self) -> Response {
-        StatusCode::NO_CONTENT.into_response()
+    // Keep accepting connections, so that new requests are answered with 503
+    // and providers can still submit work they acquired before.
+    while !state.is_drained() && Instant::now() < deadline {
+        sleep(DRAIN_POLL_INTERVAL).await;
     }
-}
-
-#[axum_macros::debug_handler(state = AppState)]
-async fn acquire(
-    _: AcquirePath,
-    State(hub): State<&'static Hub<ProviderSelector, Job>>,
-    State(ongoing): State<&'static Ongoing<JobId, Job>>,
-    Json(req): Json<AcquireRequest>,
-) -> Result<Json<AcquireResponse>, AcquireTimeout> {
-    let selector = req.provider_secret.selector();
-    let job = timeout(Duration::from_secs(10), hub.acquire(selector))
+    stop.cancel();
+    tls_handle.graceful_shutdown(Some(deadline.saturating_duration_since(Instant::now())));
+    if timeout_at(deadline, future::join_all(servers))
         .await
-        .map_err(|_: Elapsed| AcquireTimeout)?;
-    let id = JobId::random();
-    let response = AcquireResponse {
-        id: id.clone(),
-        engine: job.engine.clone(),
-        work: job.work.clone(),
-    };
-    ongoing.add(id, job);
-    Ok(Json(response))
-}
-
-#[derive(TypedPath, Deserialize)]
-#[typed_path("/api/external-engine/work/:id")]
-struct SubmitPath {
-    id: JobId,
-}
-
-#[axum_macros::debug_handler(state = AppState)]
-async fn submit(
-    SubmitPath { id }: SubmitPath,
-    State(ongoing): State<&'static Ongoing<JobId, Job>>,
-    body: BodyStream,
-) -> Result<(), Error> {
-    let work = ongoing.remove(&id).ok_or(Error::WorkNotFound)?;
-    let (tx, rx) = mpsc::channel(1);
-    let _: Result<(), _> = work.tx.send(rx);
-
-    let stream = body.map_err(|err| io::Error::new(io::ErrorKind::Other, err));
-    let read = StreamReader::new(stream);
-    let mut lines = read.lines();
-
-    let mut emit = Emit::default();
-
-    while let Some(line) = select! {
-        maybe_line = lines.next_line() => maybe_line?,
-        _ = tx.closed() => {
-            log::info!("requester gone away");
-            None
-        },
-    } {
-        if let Some(uci) = UciOut::from_line(&line)? {
-            emit.update(&uci, &work.pos);
-
-            if matches!(uci, UciOut::Bestmove { .. }) {
-                break;
-            }
-
-            if emit.should_emit() && tx.send(emit.clone()).await.is_err() {
-                log::info!("requester suddenly gone away");
-                break;
-            }
-        }
+        .is_err()
+    {
+        log::warn!("shutdown timeout elapsed, dropping remaining connections");
     }
-    Ok(())
 }
@@ -0,0 +1,191 @@
+use std::{
+    fmt::Write as _,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use crate::AppState;
+
+#[derive(Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Default)]
+pub struct Metrics {
+    pub analyse: Counter,
+    pub analyse_resumed: Counter,
+    pub rate_limited: Counter,
+    pub provider_timeouts: Counter,
+    pub superseded: Counter,
+    pub cancelled: Counter,
+    pub acquire: Counter,
+    pub acquire_timeouts: Counter,
+    pub requeued: Counter,
+    pub failed: Counter,
+    pub submit: Counter,
+    pub sockets: Counter,
+    pub socket_jobs: Counter,
+    pub socket_lines_dropped: Counter,
+    pub provider_lines: Counter,
+    pub lenient_lines: Counter,
+    pub emitted_lines: Counter,
+}
+
+/// Renders broker metrics in the Prometheus text exposition format.
+pub fn render(state: &AppState) -> String {
+    let metrics = state.metrics;
+    let mut out = String::new();
+
+    for (name, help, counter) in [
+        (
+            "analyse_requests_total",
+            "Accepted analyse requests.",
+            &metrics.analyse,
+        ),
+        (
+            "analyse_resumed_total",
+            "Analyse requests served from an existing job.",
+            &metrics.analyse_resumed,
+        ),
+        (
+            "analyse_rate_limited_total",
+            "Analyse requests rejected by rate limits.",
+            &metrics.rate_limited,
+        ),
+        (
+            "provider_timeouts_total",
+            "Analyse requests that no provider picked up in time.",
+            &metrics.provider_timeouts,
+        ),
+        (
+            "jobs_superseded_total",
+            "Jobs cancelled by new work for the same session.",
+            &metrics.superseded,
+        ),
+        (
+            "jobs_cancelled_total",
+            "Jobs cancelled by clients.",
+            &metrics.cancelled,
+        ),
+        (
+            "acquire_requests_total",
+            "Acquire long-polls.",
+            &metrics.acquire,
+        ),
+        (
+            "acquire_timeouts_total",
+            "Acquire long-polls that ended without work.",
+            &metrics.acquire_timeouts,
+        ),
+        (
+            "jobs_requeued_total",
+            "Acquired jobs handed to another instance because they were not submitted.",
+            &metrics.requeued,
+        ),
+        (
+            "jobs_failed_total",
+            "Acquired jobs given up on after too many requeues.",
+            &metrics.failed,
+        ),
+        (
+            "submit_requests_total",
+            "Submit requests for known work.",
+            &metrics.submit,
+        ),
+        (
+            "provider_sockets_total",
+            "WebSocket connections of providers.",
+            &metrics.sockets,
+        ),
+        (
+            "socket_jobs_total",
+            "Jobs pushed to providers over WebSockets.",
+            &metrics.socket_jobs,
+        ),
+        (
+            "socket_lines_dropped_total",
+            "UCI lines from provider sockets dropped because the client was too slow.",
+            &metrics.socket_lines_dropped,
+        ),
+        (
+            "provider_lines_total",
+            "UCI lines received from providers.",
+            &metrics.provider_lines,
+        ),
+        (
+            "lenient_lines_total",
+            "UCI lines from providers with unknown info tokens that were skipped.",
+            &metrics.lenient_lines,
+        ),
+        (
+            "emitted_lines_total",
+            "Analysis lines sent to clients.",
+            &metrics.emitted_lines,
+        ),
+    ] {
+        write_header(&mut out, name, "counter", help);
+        let _ = writeln!(out, "lila_engine_{name} {}", counter.get());
+    }
+
+    write_header(
+        &mut out,
+        "hub_rejected_total",
+        "counter",
+        "Jobs refused because the provider queue was full.",
+    );
+    let _ = writeln!(
+        out,
+        "lila_engine_hub_rejected_total {}",
+        state.hub.rejected()
+    );
+
+    write_header(
+        &mut out,
+        "hub_queue_depth",
+        "gauge",
+        "Queued jobs per provider selector.",
+    );
+    for (selector, depth) in state.hub.queue_depths() {
+        let _ = writeln!(
+            out,
+            "lila_engine_hub_queue_depth{{selector=\"{selector}\"}} {depth}"
+        );
+    }
+
+    for (name, help, len) in [
+        (
+            "ongoing_jobs",
+            "Acquired jobs waiting for submission.",
+            state.ongoing.len(),
+        ),
+        ("sessions", "Tracked client sessions.", state.sessions.len()),
+        (
+            "progress_entries",
+            "Jobs available for status lookups.",
+            state.progress.len(),
+        ),
+        (
+            "provider_instances",
+            "Provider instances that recently acquired or submitted work.",
+            state.instances.len(),
+        ),
+    ] {
+        write_header(&mut out, name, "gauge", help);
+        let _ = writeln!(out, "lila_engine_{name} {len}");
+    }
+
+    out
+}
+
+fn write_header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP lila_engine_{name} {help}");
+    let _ = writeln!(out, "# TYPE lila_engine_{name} {kind}");
+}
@@ -6,7 +6,7 @@ use shakmaty::variant::Variant;
 
 use crate::model::{ClientSecret, UciVariant, UserId};
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
 pub struct EngineId(pub String);
 
 impl fmt::Display for EngineId {
@@ -15,7 +15,7 @@ impl fmt::Display for EngineId {
     }
 }
 
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Engine {
     pub id: EngineId,
     #[serde(flatten)]
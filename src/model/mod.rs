@@ -14,8 +14,17 @@ pub use multi_pv::{InvalidMultiPvError, MultiPv};
 pub use provider_secret::{ProviderSecret, ProviderSelector};
 pub use uci_variant::UciVariant;
 
-#[derive(Deserialize, Serialize, Debug, Clone)]
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq, Hash)]
 pub struct UserId(String);
 
-#[derive(Deserialize, Serialize, Debug, Clone)]
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq, Hash)]
 pub struct SessionId(String);
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct InstanceId(String);
+
+impl From<String> for InstanceId {
+    fn from(id: String) -> InstanceId {
+        InstanceId(id)
+    }
+}
@@ -5,6 +5,13 @@ use thiserror::Error;
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
 pub struct MultiPv(u32);
 
+impl MultiPv {
+    /// Upper bound accepted from engines. Clients are limited further by
+    /// `Limits::max_multi_pv`, and engine lines beyond the requested number
+    /// of principal variations are ignored.
+    pub const MAX: MultiPv = MultiPv(500);
+}
+
 impl Default for MultiPv {
     fn default() -> MultiPv {
         MultiPv(1)
@@ -12,14 +19,14 @@ impl Default for MultiPv {
 }
 
 #[derive(Error, Debug)]
-#[error("supported range is 1 to 5")]
+#[error("supported range is 1 to {}", MultiPv::MAX.0)]
 pub struct InvalidMultiPvError;
 
 impl TryFrom<u32> for MultiPv {
     type Error = InvalidMultiPvError;
 
     fn try_from(n: u32) -> Result<MultiPv, InvalidMultiPvError> {
-        if n <= 5 {
+        if n <= MultiPv::MAX.0 {
             Ok(MultiPv(max(1, n)))
         } else {
             Err(InvalidMultiPvError)
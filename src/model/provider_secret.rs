@@ -1,9 +1,17 @@
-use serde::Deserialize;
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct ProviderSecret(String);
 
+impl From<String> for ProviderSecret {
+    fn from(secret: String) -> ProviderSecret {
+        ProviderSecret(secret)
+    }
+}
+
 impl ProviderSecret {
     pub fn selector(&self) -> ProviderSelector {
         let mut hasher = Sha256::new();
@@ -15,3 +23,9 @@ impl ProviderSecret {
 
 #[derive(Deserialize, Eq, PartialEq, Hash, Debug, Clone)]
 pub struct ProviderSelector(String);
+
+impl fmt::Display for ProviderSelector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
@@ -1,20 +1,21 @@
 use std::{
     array,
     collections::{hash_map::RandomState, HashMap},
-    hash::{BuildHasher, Hash, Hasher},
+    hash::{BuildHasher, Hash},
     sync::Mutex,
     time::Duration,
 };
 
 use tokio::time::sleep;
 
-use crate::hub::IsValid;
+use crate::hub::{Heartbeat, IsValid, ShardStats};
 
 const NUM_SHARDS: usize = 128;
 
 pub struct Ongoing<S, R> {
     random_state: RandomState,
     shards: [Mutex<HashMap<S, R>>; NUM_SHARDS],
+    heartbeat: Heartbeat,
 }
 
 impl<S: Hash + Eq, R> Default for Ongoing<S, R> {
@@ -22,32 +23,68 @@ impl<S: Hash + Eq, R> Default for Ongoing<S, R> {
         Ongoing {
             random_state: RandomState::new(),
             shards: array::from_fn(|_| Mutex::new(HashMap::new())),
+            heartbeat: Heartbeat::default(),
         }
     }
 }
 
 impl<S: Hash + Eq, R> Ongoing<S, R> {
-    pub fn add(&self, selector: S, item: R) {
-        self.shard(&selector).lock().unwrap().insert(selector, item);
+    pub fn add(&self, selector: S, item: R) -> Option<R> {
+        self.shard(&selector).lock().unwrap().insert(selector, item)
+    }
+
+    pub fn get(&self, selector: &S) -> Option<R>
+    where
+        R: Clone,
+    {
+        self.shard(selector).lock().unwrap().get(selector).cloned()
     }
 
     pub fn remove(&self, selector: &S) -> Option<R> {
         self.shard(selector).lock().unwrap().remove(selector)
     }
 
+    /// Whether any entry satisfies `f`. Shards are locked one at a time.
+    pub fn any(&self, f: impl Fn(&S, &R) -> bool) -> bool {
+        self.shards.iter().any(|shard| {
+            shard
+                .lock()
+                .unwrap()
+                .iter()
+                .any(|(selector, item)| f(selector, item))
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.lock().unwrap().len())
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn stats(&self) -> ShardStats {
+        ShardStats::new(
+            self.shards.iter().map(|shard| shard.lock().unwrap().len()),
+            &self.heartbeat,
+        )
+    }
+
     fn shard(&self, selector: &S) -> &Mutex<HashMap<S, R>> {
-        let mut hasher = self.random_state.build_hasher();
-        selector.hash(&mut hasher);
-        &self.shards[hasher.finish() as usize % NUM_SHARDS]
+        &self.shards[self.random_state.hash_one(selector) as usize % NUM_SHARDS]
     }
 }
 
 impl<S, R: IsValid> Ongoing<S, R> {
-    pub async fn garbage_collect(&self) {
+    pub async fn garbage_collect(&self, interval: Duration) {
         loop {
             for shard in &self.shards {
                 shard.lock().unwrap().retain(|_, item| item.is_valid());
-                sleep(Duration::from_secs(7)).await;
+                self.heartbeat.beat();
+                sleep(interval).await;
             }
         }
     }
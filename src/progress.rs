@@ -0,0 +1,189 @@
+use std::{
+    ops::Deref,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use futures::{stream, Stream};
+use serde::Serialize;
+use tokio::{sync::watch, time::Instant};
+use tokio_util::sync::{CancellationToken, WaitForCancellationFuture};
+
+use crate::{api::Work, emit::Emit, hub::IsValid, model::JobId};
+
+/// How long a finished job can still be looked up and replayed.
+const REPLAY_TTL: Duration = Duration::from_secs(60);
+
+#[derive(Serialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum Status {
+    Queued,
+    Acquired,
+    Streaming,
+    Finished,
+    Cancelled,
+    Failed,
+}
+
+impl Status {
+    pub fn is_done(self) -> bool {
+        matches!(self, Status::Finished | Status::Cancelled | Status::Failed)
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Snapshot {
+    pub status: Status,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub emit: Option<Emit>,
+    #[serde(skip)]
+    emitted: usize,
+}
+
+/// State of a job that is shared between the client session and whoever
+/// currently holds the job (hub, ongoing, or a submitting provider).
+pub struct Progress {
+    id: JobId,
+    work: Work,
+    cancel: CancellationToken,
+    snapshot: watch::Sender<Snapshot>,
+    done_at: Mutex<Option<Instant>>,
+}
+
+impl Progress {
+    pub fn new(id: JobId, work: Work) -> Progress {
+        let (snapshot, _) = watch::channel(Snapshot {
+            status: Status::Queued,
+            emit: None,
+            emitted: 0,
+        });
+        Progress {
+            id,
+            work,
+            cancel: CancellationToken::new(),
+            snapshot,
+            done_at: Mutex::new(None),
+        }
+    }
+
+    pub fn id(&self) -> &JobId {
+        &self.id
+    }
+
+    pub fn work(&self) -> &Work {
+        &self.work
+    }
+
+    pub fn cancel(&self) {
+        self.cancel.cancel();
+        self.finish(Status::Cancelled);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancel.is_cancelled()
+    }
+
+    pub fn cancelled(&self) -> WaitForCancellationFuture<'_> {
+        self.cancel.cancelled()
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        self.snapshot.borrow().clone()
+    }
+
+    pub fn is_live(&self) -> bool {
+        !self.snapshot.borrow().status.is_done()
+    }
+
+    /// A later request for the same work can pick up where this one left
+    /// off, rather than starting a new search. That is while the search is
+    /// still running, or once it completed with a best move, but not if it
+    /// was stopped early.
+    pub fn is_resumable(&self) -> bool {
+        let snapshot = self.snapshot.borrow();
+        !self.is_cancelled()
+            && match snapshot.emit {
+                Some(ref emit) => emit.is_final() || !snapshot.status.is_done(),
+                None => false,
+            }
+    }
+
+    pub fn set_status(&self, status: Status) {
+        self.snapshot.send_modify(|snapshot| {
+            if !snapshot.status.is_done() {
+                snapshot.status = status;
+            }
+        });
+    }
+
+    pub fn set_emit(&self, emit: Emit) {
+        self.snapshot.send_modify(|snapshot| {
+            snapshot.emit = Some(emit);
+            snapshot.emitted += 1;
+        });
+    }
+
+    /// Gives up on the job, because no provider took care of it, or the
+    /// provider went away during the search.
+    pub fn fail(&self) {
+        self.finish(Status::Failed);
+    }
+
+    fn finish(&self, status: Status) {
+        self.set_status(status);
+        self.done_at
+            .lock()
+            .unwrap()
+            .get_or_insert_with(Instant::now);
+    }
+
+    /// Streams the latest emit, followed by any further emits until the job
+    /// is done.
+    pub fn replay(&self) -> impl Stream<Item = Emit> {
+        let rx = self.snapshot.subscribe();
+        stream::unfold(Some((rx, 0)), |state| async move {
+            let (mut rx, mut seen) = state?;
+            loop {
+                let snapshot = rx.borrow_and_update().clone();
+                match snapshot.emit {
+                    Some(emit) if snapshot.emitted > seen => {
+                        seen = snapshot.emitted;
+                        let next = (!snapshot.status.is_done()).then_some((rx, seen));
+                        return Some((emit, next));
+                    }
+                    _ if snapshot.status.is_done() => return None,
+                    _ => rx.changed().await.ok()?,
+                }
+            }
+        })
+    }
+}
+
+impl IsValid for Arc<Progress> {
+    fn is_valid(&self) -> bool {
+        !matches!(*self.done_at.lock().unwrap(), Some(done_at) if done_at.elapsed() >= REPLAY_TTL)
+    }
+}
+
+/// Marks the job as finished when the job itself is dropped.
+pub struct ProgressGuard(Arc<Progress>);
+
+impl ProgressGuard {
+    pub fn new(progress: Arc<Progress>) -> ProgressGuard {
+        ProgressGuard(progress)
+    }
+}
+
+impl Deref for ProgressGuard {
+    type Target = Progress;
+
+    fn deref(&self) -> &Progress {
+        &self.0
+    }
+}
+
+impl Drop for ProgressGuard {
+    fn drop(&mut self) {
+        self.0.finish(Status::Finished);
+    }
+}
@@ -0,0 +1,312 @@
+use std::{
+    ffi::OsString,
+    io,
+    path::{Path, PathBuf},
+    process::Stdio,
+    sync::Arc,
+    time::Duration,
+};
+
+use axum::http::{
+    header::{CONTENT_TYPE, HOST},
+    uri::{InvalidUri, Scheme},
+    Method, Request, Response, StatusCode, Uri,
+};
+use hyper::{body, client::conn, Body};
+use thiserror::Error;
+use tokio::{
+    io::{AsyncBufReadExt as _, AsyncRead, AsyncWrite, AsyncWriteExt as _, BufReader, Lines},
+    net::TcpStream,
+    process::{Child, ChildStdin, ChildStdout, Command},
+    select, task,
+    time::sleep,
+};
+use tokio_rustls::{
+    rustls::{ClientConfig, OwnedTrustAnchor, RootCertStore, ServerName},
+    TlsConnector,
+};
+
+use crate::{
+    api::{AcquireRequest, AcquireResponse, Instance},
+    model::{JobId, ProviderSecret},
+    uci::{Parsing, UciIn, UciOut},
+};
+
+#[derive(Error, Debug)]
+pub enum ProviderError {
+    #[error("io error: {0}")]
+    Io(#[from] io::Error),
+    #[error("http error: {0}")]
+    Http(#[from] hyper::Error),
+    #[error("invalid endpoint: {0}")]
+    InvalidUri(#[from] InvalidUri),
+    #[error("invalid response: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("unexpected status: {0}")]
+    Status(StatusCode),
+    #[error("engine exited")]
+    EngineExited,
+}
+
+/// Where to find work, and the local engine to analyse it with.
+#[derive(Debug, Clone)]
+pub struct ProviderConfig {
+    /// Base URL of the broker, like `https://engine.lichess.ovh`.
+    pub endpoint: Uri,
+    pub provider_secret: ProviderSecret,
+    pub instance: Option<Instance>,
+    /// UCI engine executable and its arguments.
+    pub engine: PathBuf,
+    pub engine_args: Vec<OsString>,
+    /// Pause after failing to reach the broker.
+    pub retry_after: Duration,
+    /// How to treat unknown tokens in engine output. Lines that do not
+    /// parse are not sent to the broker, so this should match the broker.
+    pub uci_parsing: Parsing,
+}
+
+/// Serves work from the broker with a local UCI engine, until the engine
+/// exits.
+pub async fn run(config: ProviderConfig) -> Result<(), ProviderError> {
+    let client = Client::new(config.endpoint.clone());
+    let mut engine = Engine::spawn(&config.engine, &config.engine_args).await?;
+    let req = AcquireRequest {
+        provider_secret: config.provider_secret.clone(),
+        instance: config.instance.clone(),
+    };
+
+    loop {
+        let acquired = match client.acquire(&req).await {
+            Ok(Some(acquired)) => acquired,
+            Ok(None) => continue,
+            Err(err) => {
+                log::warn!("failed to acquire work: {err}");
+                sleep(config.retry_after).await;
+                continue;
+            }
+        };
+        log::info!("analysing {} with {}", acquired.id, acquired.engine.id);
+        analyse(&client, &mut engine, acquired, config.uci_parsing).await?;
+    }
+}
+
+/// Runs the search for acquired work, and streams the engine output to the
+/// broker. Returns once the engine is ready for the next work.
+async fn analyse(
+    client: &Client,
+    engine: &mut Engine,
+    acquired: AcquireResponse,
+    uci_parsing: Parsing,
+) -> Result<(), ProviderError> {
+    // Submit on a separate task, so that the request makes progress while
+    // waiting for the engine or for room in the body. Start it right away,
+    // because the broker hands the work to another instance if submitting
+    // does not start in time.
+    let (mut body, req_body) = Body::channel();
+    let mut submit = task::spawn({
+        let client = client.clone();
+        let id = acquired.id.clone();
+        async move { client.submit(&id, req_body).await }
+    });
+    let mut submitted = None;
+
+    for command in acquired.work.to_uci_commands() {
+        engine.send(&command).await?;
+        if command == UciIn::Isready {
+            engine.recv_until("readyok").await?;
+        }
+    }
+
+    loop {
+        let line = select! {
+            res = &mut submit, if submitted.is_none() => {
+                // Cancelled, or the client is gone.
+                submitted = Some(res);
+                engine.send(&UciIn::Stop).await?;
+                continue;
+            }
+            line = engine.recv() => line?,
+        };
+        let bestmove = line.split_whitespace().next() == Some("bestmove");
+        match UciOut::from_line(&line, uci_parsing) {
+            Ok(Some(_)) if submitted.is_none() => {
+                if body.send_data(format!("{line}\n").into()).await.is_err() {
+                    log::debug!("submit body closed");
+                }
+            }
+            Ok(_) => (),
+            Err(err) => log::warn!("skipping invalid line from engine: {line:?}: {err}"),
+        }
+        if bestmove {
+            break;
+        }
+    }
+
+    drop(body);
+    let res = match submitted {
+        Some(res) => res,
+        None => submit.await,
+    };
+    match res.map_err(io::Error::from)? {
+        Ok(status) if status.is_success() => log::info!("submitted {}", acquired.id),
+        Ok(status) => log::info!("submit of {} ended with {status}", acquired.id),
+        Err(err) => log::warn!("failed to submit {}: {err}", acquired.id),
+    }
+    Ok(())
+}
+
+/// A local UCI engine process.
+struct Engine {
+    _child: Child,
+    stdin: ChildStdin,
+    stdout: Lines<BufReader<ChildStdout>>,
+}
+
+impl Engine {
+    async fn spawn(path: &Path, args: &[OsString]) -> Result<Engine, ProviderError> {
+        let mut child = Command::new(path)
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()?;
+        let mut engine = Engine {
+            stdin: child.stdin.take().expect("piped stdin"),
+            stdout: BufReader::new(child.stdout.take().expect("piped stdout")).lines(),
+            _child: child,
+        };
+        engine.send(&UciIn::Uci).await?;
+        engine.recv_until("uciok").await?;
+        Ok(engine)
+    }
+
+    async fn send(&mut self, command: &UciIn) -> Result<(), ProviderError> {
+        log::debug!("<< {command}");
+        self.stdin
+            .write_all(format!("{command}\n").as_bytes())
+            .await?;
+        self.stdin.flush().await?;
+        Ok(())
+    }
+
+    async fn recv(&mut self) -> Result<String, ProviderError> {
+        let line = self
+            .stdout
+            .next_line()
+            .await?
+            .ok_or(ProviderError::EngineExited)?;
+        log::debug!(">> {line}");
+        Ok(line)
+    }
+
+    async fn recv_until(&mut self, token: &str) -> Result<(), ProviderError> {
+        while self.recv().await?.trim() != token {}
+        Ok(())
+    }
+}
+
+/// Minimal HTTP/1.1 client, with a new connection for each request.
+#[derive(Clone)]
+struct Client {
+    endpoint: Uri,
+    tls: TlsConnector,
+}
+
+impl Client {
+    fn new(endpoint: Uri) -> Client {
+        let mut roots = RootCertStore::empty();
+        roots.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
+            OwnedTrustAnchor::from_subject_spki_name_constraints(
+                ta.subject,
+                ta.spki,
+                ta.name_constraints,
+            )
+        }));
+        let config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        Client {
+            endpoint,
+            tls: TlsConnector::from(Arc::new(config)),
+        }
+    }
+
+    /// Long-polls for work. `None` if there was none.
+    async fn acquire(
+        &self,
+        req: &AcquireRequest,
+    ) -> Result<Option<AcquireResponse>, ProviderError> {
+        let res = self
+            .request(
+                Method::POST,
+                "/api/external-engine/work",
+                Body::from(serde_json::to_vec(req)?),
+            )
+            .await?;
+        match res.status() {
+            StatusCode::OK => Ok(Some(serde_json::from_slice(
+                &body::to_bytes(res.into_body()).await?,
+            )?)),
+            StatusCode::NO_CONTENT => Ok(None),
+            status => Err(ProviderError::Status(status)),
+        }
+    }
+
+    /// Streams engine output for the work. Completes when the broker is done
+    /// with the work, which may be before the body ends.
+    async fn submit(&self, id: &JobId, body: Body) -> Result<StatusCode, ProviderError> {
+        let res = self
+            .request(
+                Method::POST,
+                &format!("/api/external-engine/work/{id}"),
+                body,
+            )
+            .await?;
+        Ok(res.status())
+    }
+
+    async fn request(
+        &self,
+        method: Method,
+        path: &str,
+        body: Body,
+    ) -> Result<Response<Body>, ProviderError> {
+        let host = self.endpoint.host().unwrap_or("localhost");
+        let https = self.endpoint.scheme() == Some(&Scheme::HTTPS);
+        let port = self
+            .endpoint
+            .port_u16()
+            .unwrap_or(if https { 443 } else { 80 });
+        let base = self.endpoint.path().trim_end_matches('/');
+        let req = Request::builder()
+            .method(method)
+            .uri(format!("{base}{path}").parse::<Uri>()?)
+            .header(HOST, self.endpoint.authority().map_or(host, |a| a.as_str()))
+            .header(CONTENT_TYPE, "application/json")
+            .body(body)
+            .expect("request");
+
+        let tcp = TcpStream::connect((host, port)).await?;
+        if https {
+            let name = ServerName::try_from(host)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+            send(self.tls.connect(name, tcp).await?, req).await
+        } else {
+            send(tcp, req).await
+        }
+    }
+}
+
+async fn send<T>(io: T, req: Request<Body>) -> Result<Response<Body>, ProviderError>
+where
+    T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (mut sender, connection) = conn::handshake(io).await?;
+    task::spawn(async move {
+        if let Err(err) = connection.await {
+            log::debug!("connection error: {err}");
+        }
+    });
+    Ok(sender.send_request(req).await?)
+}
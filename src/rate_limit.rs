@@ -0,0 +1,166 @@
+use std::{
+    array,
+    collections::{hash_map::RandomState, HashMap},
+    hash::{BuildHasher, Hash},
+    sync::Mutex,
+    time::Duration,
+};
+
+use tokio::time::{sleep, Instant};
+
+const NUM_SHARDS: usize = 64;
+
+#[derive(Debug, Copy, Clone)]
+pub struct RateLimit {
+    /// Number of requests that can be made in quick succession. 0 disables
+    /// the limit.
+    pub burst: u32,
+    /// Sustained number of requests per minute.
+    pub per_minute: u32,
+}
+
+impl RateLimit {
+    fn interval(&self) -> Duration {
+        Duration::from_secs(60) / self.per_minute.max(1)
+    }
+}
+
+/// Token buckets keyed by `K`, implemented as a generic cell rate algorithm:
+/// each key only needs to remember the time at which its bucket will be full
+/// again.
+pub struct RateLimiter<K> {
+    limit: RateLimit,
+    random_state: RandomState,
+    shards: [Mutex<HashMap<K, Instant>>; NUM_SHARDS],
+}
+
+impl<K: Hash + Eq> RateLimiter<K> {
+    pub fn new(limit: RateLimit) -> RateLimiter<K> {
+        RateLimiter {
+            limit,
+            random_state: RandomState::new(),
+            shards: array::from_fn(|_| Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Returns how long to wait until the next token for `key` is
+    /// available, without taking it.
+    pub fn peek(&self, key: &K) -> Result<(), Duration> {
+        if self.limit.burst == 0 {
+            return Ok(());
+        }
+        let now = Instant::now();
+        match self.shard(key).lock().unwrap().get(key) {
+            Some(&full_at) => self.wait(full_at, now).map_or(Ok(()), Err),
+            None => Ok(()),
+        }
+    }
+
+    /// Takes a token for `key`, or returns how long to wait until the next
+    /// token is available.
+    pub fn check(&self, key: K) -> Result<(), Duration> {
+        if self.limit.burst == 0 {
+            return Ok(());
+        }
+        let now = Instant::now();
+        let mut shard = self.shard(&key).lock().unwrap();
+        let full_at = shard.entry(key).or_insert(now);
+        if let Some(wait) = self.wait(*full_at, now) {
+            return Err(wait);
+        }
+        *full_at = (*full_at).max(now) + self.limit.interval();
+        Ok(())
+    }
+
+    fn wait(&self, full_at: Instant, now: Instant) -> Option<Duration> {
+        let tolerance = self.limit.interval() * (self.limit.burst - 1);
+        (full_at.max(now) - now)
+            .checked_sub(tolerance)
+            .filter(|wait| !wait.is_zero())
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.lock().unwrap().len())
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn shard(&self, key: &K) -> &Mutex<HashMap<K, Instant>> {
+        &self.shards[self.random_state.hash_one(key) as usize % NUM_SHARDS]
+    }
+
+    pub async fn garbage_collect(&self, interval: Duration) {
+        loop {
+            for shard in &self.shards {
+                let now = Instant::now();
+                shard.lock().unwrap().retain(|_, full_at| *full_at > now);
+                sleep(interval).await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_check() {
+        let limiter = RateLimiter::new(RateLimit {
+            burst: 3,
+            per_minute: 6,
+        });
+
+        assert!(limiter.check("a").is_ok());
+        assert!(limiter.check("a").is_ok());
+        assert!(limiter.check("a").is_ok());
+        assert_eq!(limiter.check("a"), Err(Duration::from_secs(10)));
+        assert!(limiter.check("b").is_ok());
+
+        sleep(Duration::from_secs(4)).await;
+        assert_eq!(limiter.check("a"), Err(Duration::from_secs(6)));
+        sleep(Duration::from_secs(6)).await;
+        assert!(limiter.check("a").is_ok());
+        assert!(limiter.check("a").is_err());
+
+        sleep(Duration::from_secs(30)).await;
+        assert!(limiter.check("a").is_ok());
+        assert!(limiter.check("a").is_ok());
+        assert!(limiter.check("a").is_ok());
+        assert!(limiter.check("a").is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_peek() {
+        let limiter = RateLimiter::new(RateLimit {
+            burst: 1,
+            per_minute: 6,
+        });
+
+        for _ in 0..3 {
+            assert!(limiter.peek(&"a").is_ok());
+        }
+        assert!(limiter.check("a").is_ok());
+        assert_eq!(limiter.peek(&"a"), Err(Duration::from_secs(10)));
+        assert_eq!(limiter.check("a"), Err(Duration::from_secs(10)));
+        sleep(Duration::from_secs(10)).await;
+        assert!(limiter.peek(&"a").is_ok());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_unlimited() {
+        let limiter = RateLimiter::new(RateLimit {
+            burst: 0,
+            per_minute: 0,
+        });
+        for _ in 0..1000 {
+            assert!(limiter.check(1).is_ok());
+        }
+        assert!(limiter.is_empty());
+    }
+}
@@ -0,0 +1,95 @@
+use std::{collections::HashMap, fs::File, io, io::BufReader, path::Path};
+
+use futures::future::{self, BoxFuture, FutureExt as _};
+
+use crate::{
+    model::{ClientSecret, EngineId},
+    repo::{EngineRegistry, ExternalEngine, RegistryError},
+};
+
+/// Engine registry that does not need a database, for local development and
+/// tests.
+pub struct MemoryRepo {
+    engines: HashMap<EngineId, ExternalEngine>,
+}
+
+impl MemoryRepo {
+    pub fn new(engines: impl IntoIterator<Item = ExternalEngine>) -> MemoryRepo {
+        MemoryRepo {
+            engines: engines
+                .into_iter()
+                .map(|engine| (engine.id.clone(), engine))
+                .collect(),
+        }
+    }
+
+    /// Loads a JSON array of external engine records, in the same format as
+    /// the documents in the `external_engine` collection.
+    pub fn from_file(path: &Path) -> io::Result<MemoryRepo> {
+        let engines: Vec<ExternalEngine> =
+            serde_json::from_reader(BufReader::new(File::open(path)?))?;
+        Ok(MemoryRepo::new(engines))
+    }
+}
+
+impl EngineRegistry for MemoryRepo {
+    fn find(
+        &'static self,
+        id: EngineId,
+        client_secret: ClientSecret,
+    ) -> BoxFuture<'static, Result<Option<ExternalEngine>, RegistryError>> {
+        future::ready(Ok(self
+            .engines
+            .get(&id)
+            .filter(|e| e.config.client_secret == client_secret)
+            .cloned()))
+        .boxed()
+    }
+
+    fn ping(&'static self) -> BoxFuture<'static, Result<(), RegistryError>> {
+        future::ready(Ok(())).boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_find() {
+        let repo: &'static MemoryRepo = Box::leak(Box::new(MemoryRepo::new(
+            serde_json::from_str::<Vec<ExternalEngine>>(
+                r#"[{
+                    "_id": "eei_aTKImBJOnv6j",
+                    "providerSelector": "6e4b7a1bd9b1d3ffd8bb5a89e0e1e1f3",
+                    "name": "Stockfish 15",
+                    "clientSecret": "ees_mdF2hK0hlKGSPeC6",
+                    "userId": "revoof",
+                    "maxThreads": 8,
+                    "maxHash": 2048,
+                    "variants": ["chess"],
+                    "providerData": null
+                }]"#,
+            )
+            .unwrap(),
+        )));
+
+        let id = EngineId("eei_aTKImBJOnv6j".to_owned());
+        let secret = |s: &str| serde_json::from_value(s.into()).unwrap();
+
+        assert!(repo
+            .find(id.clone(), secret("ees_mdF2hK0hlKGSPeC6"))
+            .await
+            .unwrap()
+            .is_some());
+        assert!(repo.find(id, secret("ees_wrong")).await.unwrap().is_none());
+        assert!(repo
+            .find(
+                EngineId("eei_missing".to_owned()),
+                secret("ees_mdF2hK0hlKGSPeC6")
+            )
+            .await
+            .unwrap()
+            .is_none());
+    }
+}
@@ -0,0 +1,52 @@
+use futures::future::BoxFuture;
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::model::{ClientSecret, Engine, EngineConfig, EngineId, ProviderSelector};
+
+mod memory;
+mod mongo;
+
+pub use memory::MemoryRepo;
+pub use mongo::MongoRepo;
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ExternalEngine {
+    #[serde(rename = "_id", alias = "id")]
+    pub id: EngineId,
+    pub provider_selector: ProviderSelector,
+    #[serde(flatten)]
+    pub config: EngineConfig,
+}
+
+impl ExternalEngine {
+    pub fn into_engine_and_selector(self) -> (Engine, ProviderSelector) {
+        (
+            Engine {
+                id: self.id,
+                config: self.config,
+            },
+            self.provider_selector,
+        )
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum RegistryError {
+    #[error("mongodb error: {0}")]
+    MongoDb(#[from] mongodb::error::Error),
+}
+
+pub trait EngineRegistry: Send + Sync {
+    /// Finds the engine with the given id, provided that the client secret
+    /// matches.
+    fn find(
+        &'static self,
+        id: EngineId,
+        client_secret: ClientSecret,
+    ) -> BoxFuture<'static, Result<Option<ExternalEngine>, RegistryError>>;
+
+    /// Checks that the backing store is reachable.
+    fn ping(&'static self) -> BoxFuture<'static, Result<(), RegistryError>>;
+}
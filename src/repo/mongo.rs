@@ -0,0 +1,63 @@
+use futures::future::{BoxFuture, FutureExt as _};
+use mongodb::{bson::doc, options::ClientOptions, Client, Collection, Database};
+use tokio::task;
+
+use crate::{
+    model::{ClientSecret, EngineId},
+    repo::{EngineRegistry, ExternalEngine, RegistryError},
+};
+
+pub struct MongoRepo {
+    db: Database,
+    coll: Collection<ExternalEngine>,
+}
+
+impl MongoRepo {
+    pub async fn new(url: &str) -> MongoRepo {
+        let client =
+            Client::with_options(ClientOptions::parse(url).await.expect("mongodb options"))
+                .expect("mongodb client");
+
+        let db = client
+            .default_database()
+            .unwrap_or_else(|| client.database("lichess"));
+
+        MongoRepo {
+            coll: db.collection("external_engine"),
+            db,
+        }
+    }
+}
+
+impl EngineRegistry for MongoRepo {
+    fn find(
+        &'static self,
+        id: EngineId,
+        client_secret: ClientSecret,
+    ) -> BoxFuture<'static, Result<Option<ExternalEngine>, RegistryError>> {
+        async move {
+            // MongoDB driver does not support cancellation.
+            task::spawn(async move {
+                self.coll
+                    .find_one(doc! { "_id": id.0 }, None)
+                    .await
+                    .map(|engine| engine.filter(|e| e.config.client_secret == client_secret))
+            })
+            .await
+            .expect("join mongodb find")
+            .map_err(RegistryError::from)
+        }
+        .boxed()
+    }
+
+    fn ping(&'static self) -> BoxFuture<'static, Result<(), RegistryError>> {
+        async move {
+            task::spawn(async move { self.db.run_command(doc! { "ping": 1 }, None).await })
+                .await
+                .expect("join mongodb ping")
+                .map(|_| ())
+                .map_err(RegistryError::from)
+        }
+        .boxed()
+    }
+}
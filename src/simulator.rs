@@ -0,0 +1,522 @@
+//! A simulated UCI engine for tests. It either plays back a script of
+//! output lines for each search, or makes up searches with random legal
+//! principal variations. Output is deterministic for a given seed.
+
+use std::{
+    cmp::{max, min},
+    collections::VecDeque,
+    io,
+    num::NonZeroUsize,
+    time::Duration,
+};
+
+use rand::{rngs::StdRng, seq::SliceRandom as _, Rng as _, SeedableRng as _};
+use shakmaty::{
+    uci::Uci,
+    variant::{Variant, VariantPosition},
+    CastlingMode, Move, Position as _,
+};
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt as _, AsyncWrite, AsyncWriteExt as _},
+    select,
+    time::{sleep_until, Instant},
+};
+
+use crate::{
+    model::MultiPv,
+    uci::{Eval, Go, Score, UciIn, UciOut},
+};
+
+/// Line injected into the output, that engines would never send.
+pub const MALFORMED_LINE: &str = "info depth ?";
+
+#[derive(Debug, Clone)]
+pub struct SimulatorConfig {
+    pub name: String,
+    /// Seed for made up searches.
+    pub seed: u64,
+    /// Depth of made up searches, unless limited by `go depth`.
+    pub max_depth: u32,
+    /// Pause between two lines of search output.
+    pub interval: Duration,
+    /// Lines to play back for each search, instead of making them up. The
+    /// `bestmove` line is held back until the search is over.
+    pub script: Option<Vec<String>>,
+    /// Inject a malformed line before every nth line of search output.
+    pub malformed_every: Option<NonZeroUsize>,
+}
+
+impl Default for SimulatorConfig {
+    fn default() -> SimulatorConfig {
+        SimulatorConfig {
+            name: "Simulator".to_owned(),
+            seed: 0,
+            max_depth: 10,
+            interval: Duration::from_millis(100),
+            script: None,
+            malformed_every: None,
+        }
+    }
+}
+
+struct Search {
+    lines: VecDeque<String>,
+    bestmove: String,
+    infinite: bool,
+    deadline: Option<Instant>,
+    next_at: Instant,
+}
+
+pub struct Simulator {
+    config: SimulatorConfig,
+    rng: StdRng,
+    variant: Variant,
+    multi_pv: MultiPv,
+    pos: VariantPosition,
+    search: Option<Search>,
+}
+
+impl Simulator {
+    pub fn new(config: SimulatorConfig) -> Simulator {
+        Simulator {
+            rng: StdRng::seed_from_u64(config.seed),
+            config,
+            variant: Variant::Chess,
+            multi_pv: MultiPv::default(),
+            pos: VariantPosition::new(Variant::Chess),
+            search: None,
+        }
+    }
+
+    /// Handles a command, and returns the immediate response.
+    pub fn handle(&mut self, command: UciIn) -> Vec<String> {
+        match command {
+            UciIn::Uci => vec![
+                format!("id name {}", self.config.name),
+                "id author lila-engine".to_owned(),
+                "option name Threads type spin default 1 min 1 max 1024".to_owned(),
+                "option name Hash type spin default 16 min 1 max 33554432".to_owned(),
+                format!(
+                    "option name MultiPV type spin default 1 min 1 max {}",
+                    MultiPv::MAX
+                ),
+                "option name UCI_Chess960 type check default false".to_owned(),
+                "option name UCI_AnalyseMode type check default false".to_owned(),
+                format!(
+                    "option name UCI_Variant type combo default chess{}",
+                    Variant::ALL
+                        .iter()
+                        .map(|v| format!(" var {}", v.uci()))
+                        .collect::<String>()
+                ),
+                "uciok".to_owned(),
+            ],
+            UciIn::Isready => vec!["readyok".to_owned()],
+            UciIn::Setoption { name, value } => {
+                let value = value.unwrap_or_default();
+                match name.as_str() {
+                    "MultiPV" => match value.parse::<u32>().ok().map(MultiPv::try_from) {
+                        Some(Ok(multi_pv)) => self.multi_pv = multi_pv,
+                        _ => return vec![format!("info string invalid MultiPV: {value}")],
+                    },
+                    "UCI_Variant" => match Variant::from_uci(&value) {
+                        Ok(variant) => self.variant = variant,
+                        Err(_) => return vec![format!("info string unknown variant: {value}")],
+                    },
+                    _ => (),
+                }
+                Vec::new()
+            }
+            UciIn::Ucinewgame => Vec::new(),
+            UciIn::Position { fen, moves } => {
+                let pos = match fen {
+                    Some(fen) => match VariantPosition::from_setup(
+                        self.variant,
+                        fen.into_setup(),
+                        CastlingMode::Chess960,
+                    ) {
+                        Ok(pos) => pos,
+                        Err(err) => return vec![format!("info string illegal position: {err}")],
+                    },
+                    None => VariantPosition::new(self.variant),
+                };
+                match play(pos, &moves) {
+                    Some(pos) => {
+                        self.pos = pos;
+                        Vec::new()
+                    }
+                    None => vec!["info string illegal move".to_owned()],
+                }
+            }
+            UciIn::Go(go) => {
+                let search = self.start(&go);
+                self.search = Some(search);
+                Vec::new()
+            }
+            UciIn::Stop => self
+                .search
+                .take()
+                .map(|search| search.bestmove)
+                .into_iter()
+                .collect(),
+            UciIn::Ponderhit => Vec::new(),
+        }
+    }
+
+    /// When the next line of search output is due, if any.
+    pub fn next_at(&self) -> Option<Instant> {
+        self.search
+            .as_ref()
+            .filter(|search| !search.lines.is_empty() || !search.infinite)
+            .map(|search| match search.deadline {
+                Some(deadline) => min(deadline, search.next_at),
+                None => search.next_at,
+            })
+    }
+
+    /// Produces the next line of search output, if it is due.
+    pub fn poll(&mut self, now: Instant) -> Option<String> {
+        let search = self.search.as_mut()?;
+        if matches!(search.deadline, Some(deadline) if now >= deadline) {
+            return self.search.take().map(|search| search.bestmove);
+        }
+        if now < search.next_at {
+            return None;
+        }
+        search.next_at = now + self.config.interval;
+        match search.lines.pop_front() {
+            Some(line) => Some(line),
+            None if search.infinite => None,
+            None => self.search.take().map(|search| search.bestmove),
+        }
+    }
+
+    fn start(&mut self, go: &Go) -> Search {
+        let (mut lines, bestmove) = match self.config.script {
+            Some(ref script) => {
+                let (bestmoves, lines): (Vec<_>, Vec<_>) = script
+                    .iter()
+                    .cloned()
+                    .partition(|line| line.starts_with("bestmove"));
+                (
+                    VecDeque::from(lines),
+                    bestmoves
+                        .into_iter()
+                        .last()
+                        .unwrap_or_else(|| "bestmove (none)".to_owned()),
+                )
+            }
+            // Search at least one ply, to have a best move.
+            None => self.make_up(
+                max(
+                    1,
+                    go.depth.map_or(self.config.max_depth, |depth| {
+                        min(depth, self.config.max_depth)
+                    }),
+                ),
+                go.searchmoves.as_deref(),
+            ),
+        };
+
+        if let Some(every) = self.config.malformed_every {
+            lines = lines
+                .into_iter()
+                .enumerate()
+                .flat_map(|(i, line)| {
+                    ((i + 1) % every.get() == 0)
+                        .then(|| MALFORMED_LINE.to_owned())
+                        .into_iter()
+                        .chain(Some(line))
+                })
+                .collect();
+        }
+
+        let now = Instant::now();
+        Search {
+            lines,
+            bestmove,
+            infinite: go.infinite,
+            deadline: go.movetime.map(|movetime| now + movetime),
+            next_at: now,
+        }
+    }
+
+    fn make_up(
+        &mut self,
+        max_depth: u32,
+        searchmoves: Option<&[Uci]>,
+    ) -> (VecDeque<String>, String) {
+        let mut roots = self.pos.legal_moves();
+        if let Some(searchmoves) = searchmoves {
+            let pos = &self.pos;
+            roots.retain(|m| {
+                searchmoves
+                    .iter()
+                    .any(|uci| matches!(uci.to_move(pos), Ok(ref sm) if sm == m))
+            });
+        }
+        if roots.is_empty() {
+            let eval = if self.pos.is_checkmate() {
+                Eval::Mate(0)
+            } else {
+                Eval::Cp(0)
+            };
+            let line = info(None, 0, 0, Duration::ZERO, eval, Vec::new());
+            return (VecDeque::from([line]), "bestmove (none)".to_owned());
+        }
+        roots.shuffle(&mut self.rng);
+        roots.truncate(usize::from(self.multi_pv));
+
+        let mut lines = VecDeque::new();
+        let mut best = Vec::new();
+        for depth in 1..=max_depth {
+            let base = self.rng.gen_range(-50..=50);
+            let nodes = 1000 * u64::from(depth) * u64::from(depth);
+            let time = self.config.interval * depth;
+            for (i, root) in roots.iter().enumerate() {
+                let pv = self.random_pv(root, depth as usize);
+                if i == 0 {
+                    best = pv.clone();
+                }
+                let eval = Eval::Cp(base - 15 * i as i64);
+                let multipv = MultiPv::try_from(i as u32 + 1).ok();
+                lines.push_back(info(multipv, depth, nodes, time, eval, pv));
+            }
+        }
+
+        let mut bestmove = format!("bestmove {}", best[0]);
+        if let Some(ponder) = best.get(1) {
+            bestmove.push_str(&format!(" ponder {ponder}"));
+        }
+        (lines, bestmove)
+    }
+
+    fn random_pv(&mut self, root: &Move, len: usize) -> Vec<Uci> {
+        let mut pos = self.pos.clone();
+        let mut pv = vec![root.to_uci(CastlingMode::Chess960)];
+        pos.play_unchecked(root);
+        while pv.len() < len {
+            let moves = pos.legal_moves();
+            let Some(m) = moves.choose(&mut self.rng) else {
+                break;
+            };
+            pv.push(m.to_uci(CastlingMode::Chess960));
+            pos.play_unchecked(m);
+        }
+        pv
+    }
+}
+
+fn play(mut pos: VariantPosition, moves: &[Uci]) -> Option<VariantPosition> {
+    for uci in moves {
+        let m = uci.to_move(&pos).ok()?;
+        pos.play_unchecked(&m);
+    }
+    Some(pos)
+}
+
+fn info(
+    multipv: Option<MultiPv>,
+    depth: u32,
+    nodes: u64,
+    time: Duration,
+    eval: Eval,
+    pv: Vec<Uci>,
+) -> String {
+    UciOut::Info {
+        multipv,
+        depth: Some(depth),
+        seldepth: None,
+        time: Some(time),
+        nodes: Some(nodes),
+        score: Some(Score {
+            eval,
+            wdl: None,
+            lowerbound: false,
+            upperbound: false,
+        }),
+        currmove: None,
+        currmovenumber: None,
+        hashfull: None,
+        nps: None,
+        tbhits: None,
+        sbhits: None,
+        cpuload: None,
+        refutation: Default::default(),
+        currline: Default::default(),
+        pv: (!pv.is_empty()).then_some(pv),
+        string: None,
+        extra: Default::default(),
+    }
+    .to_string()
+}
+
+/// Speaks UCI on the given input and output, until the input ends or `quit`.
+pub async fn run<R, W>(config: SimulatorConfig, input: R, mut output: W) -> io::Result<()>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut simulator = Simulator::new(config);
+    let mut input = input.lines();
+    loop {
+        let out = select! {
+            line = input.next_line() => {
+                let Some(line) = line? else {
+                    break;
+                };
+                match UciIn::from_line(&line) {
+                    Ok(Some(command)) => simulator.handle(command),
+                    Ok(None) if line.trim() == "quit" => break,
+                    Ok(None) => Vec::new(),
+                    Err(err) => vec![format!("info string {err}")],
+                }
+            }
+            _ = sleep_until(simulator.next_at().unwrap_or_else(Instant::now)), if simulator.next_at().is_some() => {
+                simulator.poll(Instant::now()).into_iter().collect()
+            }
+        };
+        for line in out {
+            output.write_all(format!("{line}\n").as_bytes()).await?;
+        }
+        output.flush().await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(line: &str) -> UciIn {
+        UciIn::from_line(line).unwrap().unwrap()
+    }
+
+    fn search(simulator: &mut Simulator) -> Vec<String> {
+        let mut lines = Vec::new();
+        while let Some(at) = simulator.next_at() {
+            if let Some(line) = simulator.poll(at) {
+                lines.push(line);
+            }
+        }
+        lines
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_make_up() {
+        let mut simulator = Simulator::new(SimulatorConfig {
+            max_depth: 3,
+            ..SimulatorConfig::default()
+        });
+        assert!(simulator
+            .handle(command("uci"))
+            .ends_with(&["uciok".to_owned()]));
+        assert!(simulator
+            .handle(command("setoption name MultiPV value 2"))
+            .is_empty());
+        assert!(simulator
+            .handle(command("position startpos moves e2e4"))
+            .is_empty());
+        assert!(simulator.handle(command("go depth 5")).is_empty());
+
+        let lines = search(&mut simulator);
+        assert_eq!(lines.len(), 3 * 2 + 1);
+        for line in &lines {
+            assert!(UciOut::from_line(line, crate::uci::Parsing::Strict)
+                .unwrap()
+                .is_some());
+        }
+        let Some(UciOut::Info { pv: Some(pv), .. }) =
+            UciOut::from_line(&lines[4], crate::uci::Parsing::Strict).unwrap()
+        else {
+            panic!("expected pv");
+        };
+        assert_eq!(pv.len(), 3);
+        assert!(lines[6].starts_with(&format!("bestmove {} ponder {}", pv[0], pv[1])));
+
+        // Same seed, same search.
+        let mut again = Simulator::new(SimulatorConfig {
+            max_depth: 3,
+            ..SimulatorConfig::default()
+        });
+        again.handle(command("setoption name MultiPV value 2"));
+        again.handle(command("position startpos moves e2e4"));
+        again.handle(command("go depth 5"));
+        assert_eq!(search(&mut again), lines);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_script_and_stop() {
+        let mut simulator = Simulator::new(SimulatorConfig {
+            script: Some(vec![
+                "bestmove e2e4".to_owned(),
+                "info depth 1 score cp 20 pv e2e4".to_owned(),
+                "info depth 2 score cp 30 pv e2e4 e7e5".to_owned(),
+            ]),
+            malformed_every: NonZeroUsize::new(2),
+            ..SimulatorConfig::default()
+        });
+        simulator.handle(command("go infinite"));
+        let first = simulator.next_at().unwrap();
+        assert_eq!(
+            simulator.poll(first).as_deref(),
+            Some("info depth 1 score cp 20 pv e2e4")
+        );
+        assert_eq!(simulator.poll(first), None);
+        let at = simulator.next_at().unwrap();
+        assert_eq!(simulator.poll(at).as_deref(), Some(MALFORMED_LINE));
+        let at = simulator.next_at().unwrap();
+        assert_eq!(
+            simulator.poll(at).as_deref(),
+            Some("info depth 2 score cp 30 pv e2e4 e7e5")
+        );
+
+        // Infinite searches wait for stop.
+        assert_eq!(simulator.next_at(), None);
+        assert_eq!(simulator.handle(command("stop")), ["bestmove e2e4"]);
+        assert!(simulator.handle(command("stop")).is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_searchmoves() {
+        let mut simulator = Simulator::new(SimulatorConfig {
+            max_depth: 1,
+            ..SimulatorConfig::default()
+        });
+        simulator.handle(command("setoption name MultiPV value 3"));
+        simulator.handle(command("go searchmoves e2e4 d2d4"));
+        let lines = search(&mut simulator);
+        assert_eq!(lines.len(), 2 + 1);
+        assert!(lines[..2]
+            .iter()
+            .all(|line| line.ends_with(" pv e2e4") || line.ends_with(" pv d2d4")));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_depth_zero() {
+        let mut simulator = Simulator::new(SimulatorConfig {
+            max_depth: 0,
+            ..SimulatorConfig::default()
+        });
+        simulator.handle(command("go depth 0"));
+        let lines = search(&mut simulator);
+        assert_eq!(lines.len(), 1 + 1);
+        assert!(lines[1].starts_with("bestmove "));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_no_legal_moves() {
+        let mut simulator = Simulator::new(SimulatorConfig::default());
+        simulator.handle(command(
+            "position fen rnb1kbnr/pppp1ppp/8/4p3/6Pq/5P2/PPPPP2P/RNBQKBNR w KQkq - 1 3",
+        ));
+        simulator.handle(command("go movetime 1000"));
+        assert_eq!(
+            search(&mut simulator),
+            [
+                "info depth 0 time 0 nodes 0 score mate 0",
+                "bestmove (none)"
+            ]
+        );
+    }
+}
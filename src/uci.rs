@@ -2,11 +2,45 @@ use std::{collections::HashMap, fmt, num::ParseIntError, ops::Neg, time::Duratio
 
 use memchr::{memchr2, memchr2_iter};
 use serde::Serialize;
-use shakmaty::uci::{ParseUciError, Uci};
+use shakmaty::{
+    fen::{Fen, ParseFenError},
+    uci::{ParseUciError, Uci},
+};
 use thiserror::Error;
 
 use crate::model::{InvalidMultiPvError, MultiPv};
 
+/// How to treat unknown tokens in `info` lines.
+#[derive(clap::ValueEnum, Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Parsing {
+    /// Reject the line.
+    Strict,
+    /// Skip the token and its values, up to the next known key, keeping them
+    /// as extra information.
+    Lenient,
+}
+
+/// Keys of `info` lines that are defined by the UCI protocol.
+const INFO_KEYS: [&str; 17] = [
+    "multipv",
+    "depth",
+    "seldepth",
+    "time",
+    "nodes",
+    "score",
+    "currmove",
+    "currmovenumber",
+    "hashfull",
+    "nps",
+    "tbhits",
+    "sbhits",
+    "cpuload",
+    "refutation",
+    "currline",
+    "pv",
+    "string",
+];
+
 #[derive(Error, Debug)]
 pub enum ProtocolError {
     #[error("unexpected token")]
@@ -21,11 +55,14 @@ pub enum ProtocolError {
     InvalidInteger(#[from] ParseIntError),
     #[error("invalid multipv: {0}")]
     InvalidMultipv(#[from] InvalidMultiPvError),
+    #[error("invalid fen: {0}")]
+    InvalidFen(#[from] ParseFenError),
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Score {
     pub eval: Eval,
+    pub wdl: Option<Wdl>,
     pub lowerbound: bool,
     pub upperbound: bool,
 }
@@ -33,6 +70,9 @@ pub struct Score {
 impl fmt::Display for Score {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         self.eval.fmt(f)?;
+        if let Some(wdl) = self.wdl {
+            write!(f, " {wdl}")?;
+        }
         if self.lowerbound {
             f.write_str(" lowerbound")?;
         }
@@ -70,6 +110,40 @@ impl fmt::Display for Eval {
     }
 }
 
+/// Win, draw and loss expectation in permille, as reported by engines with
+/// `UCI_ShowWDL`.
+#[derive(Serialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[serde(into = "[u32; 3]")]
+pub struct Wdl {
+    pub win: u32,
+    pub draw: u32,
+    pub loss: u32,
+}
+
+impl From<Wdl> for [u32; 3] {
+    fn from(wdl: Wdl) -> [u32; 3] {
+        [wdl.win, wdl.draw, wdl.loss]
+    }
+}
+
+impl Neg for Wdl {
+    type Output = Wdl;
+
+    fn neg(self) -> Wdl {
+        Wdl {
+            win: self.loss,
+            draw: self.draw,
+            loss: self.win,
+        }
+    }
+}
+
+impl fmt::Display for Wdl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "wdl {} {} {}", self.win, self.draw, self.loss)
+    }
+}
+
 #[derive(Debug)]
 #[allow(clippy::large_enum_variant)]
 pub enum UciOut {
@@ -95,12 +169,14 @@ pub enum UciOut {
         currline: HashMap<u32, Vec<Uci>>,
         pv: Option<Vec<Uci>>,
         string: Option<String>,
+        /// Unknown keys and their values, only with lenient parsing.
+        extra: HashMap<String, String>,
     },
 }
 
 impl UciOut {
-    pub fn from_line(s: &str) -> Result<Option<UciOut>, ProtocolError> {
-        Parser::new(s)?.parse_out()
+    pub fn from_line(s: &str, parsing: Parsing) -> Result<Option<UciOut>, ProtocolError> {
+        Parser::new(s, parsing)?.parse_out()
     }
 }
 
@@ -135,6 +211,7 @@ impl fmt::Display for UciOut {
                 currline,
                 pv,
                 string,
+                extra,
             } => {
                 f.write_str("info")?;
                 if let Some(multipv) = multipv {
@@ -188,6 +265,12 @@ impl fmt::Display for UciOut {
                         write!(f, " {m}")?;
                     }
                 }
+                for (key, value) in extra {
+                    write!(f, " {key}")?;
+                    if !value.is_empty() {
+                        write!(f, " {value}")?;
+                    }
+                }
                 if let Some(pv) = pv {
                     f.write_str(" pv")?;
                     for m in pv {
@@ -203,8 +286,138 @@ impl fmt::Display for UciOut {
     }
 }
 
+/// Commands sent to engines.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UciIn {
+    Uci,
+    Isready,
+    Setoption {
+        name: String,
+        value: Option<String>,
+    },
+    Ucinewgame,
+    Position {
+        /// The initial position, or the standard starting position if none.
+        fen: Option<Fen>,
+        moves: Vec<Uci>,
+    },
+    Go(Go),
+    Stop,
+    Ponderhit,
+}
+
+/// Search limits of a `go` command.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Go {
+    pub searchmoves: Option<Vec<Uci>>,
+    pub ponder: bool,
+    pub wtime: Option<Duration>,
+    pub btime: Option<Duration>,
+    pub winc: Option<Duration>,
+    pub binc: Option<Duration>,
+    pub movestogo: Option<u32>,
+    pub depth: Option<u32>,
+    pub nodes: Option<u64>,
+    pub mate: Option<u32>,
+    pub movetime: Option<Duration>,
+    pub infinite: bool,
+}
+
+impl UciIn {
+    pub fn from_line(s: &str) -> Result<Option<UciIn>, ProtocolError> {
+        Parser::new(s, Parsing::Strict)?.parse_in()
+    }
+
+    pub fn setoption(name: &str, value: impl ToString) -> UciIn {
+        UciIn::Setoption {
+            name: name.to_owned(),
+            value: Some(value.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for UciIn {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UciIn::Uci => f.write_str("uci"),
+            UciIn::Isready => f.write_str("isready"),
+            UciIn::Setoption { name, value } => {
+                write!(f, "setoption name {name}")?;
+                if let Some(value) = value {
+                    write!(f, " value {value}")?;
+                }
+                Ok(())
+            }
+            UciIn::Ucinewgame => f.write_str("ucinewgame"),
+            UciIn::Position { fen, moves } => {
+                match fen {
+                    Some(fen) => write!(f, "position fen {fen}")?,
+                    None => f.write_str("position startpos")?,
+                }
+                if !moves.is_empty() {
+                    f.write_str(" moves")?;
+                    for m in moves {
+                        write!(f, " {m}")?;
+                    }
+                }
+                Ok(())
+            }
+            UciIn::Go(go) => go.fmt(f),
+            UciIn::Stop => f.write_str("stop"),
+            UciIn::Ponderhit => f.write_str("ponderhit"),
+        }
+    }
+}
+
+impl fmt::Display for Go {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("go")?;
+        if let Some(searchmoves) = &self.searchmoves {
+            f.write_str(" searchmoves")?;
+            for m in searchmoves {
+                write!(f, " {m}")?;
+            }
+        }
+        if self.ponder {
+            f.write_str(" ponder")?;
+        }
+        if let Some(wtime) = self.wtime {
+            write!(f, " wtime {}", wtime.as_millis())?;
+        }
+        if let Some(btime) = self.btime {
+            write!(f, " btime {}", btime.as_millis())?;
+        }
+        if let Some(winc) = self.winc {
+            write!(f, " winc {}", winc.as_millis())?;
+        }
+        if let Some(binc) = self.binc {
+            write!(f, " binc {}", binc.as_millis())?;
+        }
+        if let Some(movestogo) = self.movestogo {
+            write!(f, " movestogo {movestogo}")?;
+        }
+        if let Some(depth) = self.depth {
+            write!(f, " depth {depth}")?;
+        }
+        if let Some(nodes) = self.nodes {
+            write!(f, " nodes {nodes}")?;
+        }
+        if let Some(mate) = self.mate {
+            write!(f, " mate {mate}")?;
+        }
+        if let Some(movetime) = self.movetime {
+            write!(f, " movetime {}", movetime.as_millis())?;
+        }
+        if self.infinite {
+            f.write_str(" infinite")?;
+        }
+        Ok(())
+    }
+}
+
 struct Parser<'a> {
     s: &'a str,
+    parsing: Parsing,
 }
 
 impl<'a> Iterator for Parser<'a> {
@@ -218,10 +431,10 @@ impl<'a> Iterator for Parser<'a> {
 }
 
 impl<'a> Parser<'a> {
-    pub fn new(s: &str) -> Result<Parser<'_>, ProtocolError> {
+    pub fn new(s: &str, parsing: Parsing) -> Result<Parser<'_>, ProtocolError> {
         match memchr2(b'\r', b'\n', s.as_bytes()) {
             Some(_) => Err(ProtocolError::UnexpectedLineBreak),
-            None => Ok(Parser { s }),
+            None => Ok(Parser { s, parsing }),
         }
     }
 
@@ -285,10 +498,28 @@ impl<'a> Parser<'a> {
             Some(_) => return Err(ProtocolError::UnexpectedToken),
             None => return Err(ProtocolError::UnexpectedEndOfLine),
         };
+        let mut wdl = None;
         let mut lowerbound = false;
         let mut upperbound = false;
         while let Some(token) = self.peek() {
             match token {
+                "wdl" => {
+                    self.next();
+                    wdl = Some(Wdl {
+                        win: self
+                            .next()
+                            .ok_or(ProtocolError::UnexpectedEndOfLine)?
+                            .parse()?,
+                        draw: self
+                            .next()
+                            .ok_or(ProtocolError::UnexpectedEndOfLine)?
+                            .parse()?,
+                        loss: self
+                            .next()
+                            .ok_or(ProtocolError::UnexpectedEndOfLine)?
+                            .parse()?,
+                    });
+                }
                 "lowerbound" => {
                     self.next();
                     lowerbound = true;
@@ -302,6 +533,7 @@ impl<'a> Parser<'a> {
         }
         Ok(Score {
             eval,
+            wdl,
             lowerbound,
             upperbound,
         })
@@ -325,6 +557,7 @@ impl<'a> Parser<'a> {
         let mut currline = HashMap::new();
         let mut pv = None;
         let mut string = None;
+        let mut extra = HashMap::new();
         loop {
             match self.next() {
                 Some("multipv") => {
@@ -433,6 +666,15 @@ impl<'a> Parser<'a> {
                 Some("string") => {
                     string = Some(self.until(|_| false).unwrap_or_default().to_owned())
                 }
+                Some(key) if self.parsing == Parsing::Lenient => {
+                    let value = match self.peek() {
+                        Some(next) if !INFO_KEYS.contains(&next) => {
+                            self.until(|token| INFO_KEYS.contains(&token))
+                        }
+                        _ => None,
+                    };
+                    extra.insert(key.to_owned(), value.unwrap_or_default().to_owned());
+                }
                 Some(_) => return Err(ProtocolError::UnexpectedToken),
                 None => break,
             }
@@ -455,9 +697,141 @@ impl<'a> Parser<'a> {
             currline,
             pv,
             string,
+            extra,
         })
     }
 
+    fn parse_setoption(&mut self) -> Result<UciIn, ProtocolError> {
+        match self.next() {
+            Some("name") => (),
+            Some(_) => return Err(ProtocolError::UnexpectedToken),
+            None => return Err(ProtocolError::UnexpectedEndOfLine),
+        }
+        let name = self
+            .until(|token| token == "value")
+            .ok_or(ProtocolError::UnexpectedEndOfLine)?
+            .to_owned();
+        let value = match self.next() {
+            Some("value") => Some(self.until(|_| false).unwrap_or_default().to_owned()),
+            Some(_) => return Err(ProtocolError::UnexpectedToken),
+            None => None,
+        };
+        Ok(UciIn::Setoption { name, value })
+    }
+
+    fn parse_position(&mut self) -> Result<UciIn, ProtocolError> {
+        let fen = match self.next() {
+            Some("startpos") => None,
+            Some("fen") => Some(
+                self.until(|token| token == "moves")
+                    .ok_or(ProtocolError::UnexpectedEndOfLine)?
+                    .parse()?,
+            ),
+            Some(_) => return Err(ProtocolError::UnexpectedToken),
+            None => return Err(ProtocolError::UnexpectedEndOfLine),
+        };
+        let moves = match self.next() {
+            Some("moves") => self.parse_moves(),
+            Some(_) => return Err(ProtocolError::UnexpectedToken),
+            None => Vec::new(),
+        };
+        match self.next() {
+            Some(_) => Err(ProtocolError::UnexpectedToken),
+            None => Ok(UciIn::Position { fen, moves }),
+        }
+    }
+
+    fn parse_go(&mut self) -> Result<UciIn, ProtocolError> {
+        let mut go = Go::default();
+        loop {
+            match self.next() {
+                Some("searchmoves") => go.searchmoves = Some(self.parse_moves()),
+                Some("ponder") => go.ponder = true,
+                Some("wtime") => {
+                    go.wtime = Some(Duration::from_millis(
+                        self.next()
+                            .ok_or(ProtocolError::UnexpectedEndOfLine)?
+                            .parse()?,
+                    ))
+                }
+                Some("btime") => {
+                    go.btime = Some(Duration::from_millis(
+                        self.next()
+                            .ok_or(ProtocolError::UnexpectedEndOfLine)?
+                            .parse()?,
+                    ))
+                }
+                Some("winc") => {
+                    go.winc = Some(Duration::from_millis(
+                        self.next()
+                            .ok_or(ProtocolError::UnexpectedEndOfLine)?
+                            .parse()?,
+                    ))
+                }
+                Some("binc") => {
+                    go.binc = Some(Duration::from_millis(
+                        self.next()
+                            .ok_or(ProtocolError::UnexpectedEndOfLine)?
+                            .parse()?,
+                    ))
+                }
+                Some("movestogo") => {
+                    go.movestogo = Some(
+                        self.next()
+                            .ok_or(ProtocolError::UnexpectedEndOfLine)?
+                            .parse()?,
+                    )
+                }
+                Some("depth") => {
+                    go.depth = Some(
+                        self.next()
+                            .ok_or(ProtocolError::UnexpectedEndOfLine)?
+                            .parse()?,
+                    )
+                }
+                Some("nodes") => {
+                    go.nodes = Some(
+                        self.next()
+                            .ok_or(ProtocolError::UnexpectedEndOfLine)?
+                            .parse()?,
+                    )
+                }
+                Some("mate") => {
+                    go.mate = Some(
+                        self.next()
+                            .ok_or(ProtocolError::UnexpectedEndOfLine)?
+                            .parse()?,
+                    )
+                }
+                Some("movetime") => {
+                    go.movetime = Some(Duration::from_millis(
+                        self.next()
+                            .ok_or(ProtocolError::UnexpectedEndOfLine)?
+                            .parse()?,
+                    ))
+                }
+                Some("infinite") => go.infinite = true,
+                Some(_) => return Err(ProtocolError::UnexpectedToken),
+                None => break,
+            }
+        }
+        Ok(UciIn::Go(go))
+    }
+
+    fn parse_in(&mut self) -> Result<Option<UciIn>, ProtocolError> {
+        Ok(Some(match self.next() {
+            Some("uci") => UciIn::Uci,
+            Some("isready") => UciIn::Isready,
+            Some("setoption") => self.parse_setoption()?,
+            Some("ucinewgame") => UciIn::Ucinewgame,
+            Some("position") => self.parse_position()?,
+            Some("go") => self.parse_go()?,
+            Some("stop") => UciIn::Stop,
+            Some("ponderhit") => UciIn::Ponderhit,
+            Some(_) | None => return Ok(None),
+        }))
+    }
+
     fn parse_out(&mut self) -> Result<Option<UciOut>, ProtocolError> {
         Ok(Some(match self.next() {
             Some("bestmove") => self.parse_bestmove()?,
@@ -527,4 +901,96 @@ mod tests {
             (Some("value abc"), "")
         );
     }
+
+    #[test]
+    fn test_parse_wdl() {
+        let line = "info depth 20 score cp 26 wdl 84 891 25 lowerbound nodes 1000 pv e2e4";
+        let Some(UciOut::Info {
+            score: Some(score),
+            nodes,
+            ..
+        }) = UciOut::from_line(line, Parsing::Strict).unwrap()
+        else {
+            panic!("expected info with score");
+        };
+        assert_eq!(
+            score,
+            Score {
+                eval: Eval::Cp(26),
+                wdl: Some(Wdl {
+                    win: 84,
+                    draw: 891,
+                    loss: 25
+                }),
+                lowerbound: true,
+                upperbound: false,
+            }
+        );
+        assert_eq!(nodes, Some(1000));
+        assert_eq!(score.to_string(), "cp 26 wdl 84 891 25 lowerbound");
+
+        assert!(matches!(
+            UciOut::from_line("info score cp 26 wdl 84 891", Parsing::Strict),
+            Err(ProtocolError::UnexpectedEndOfLine)
+        ));
+    }
+
+    #[test]
+    fn test_parse_lenient() {
+        let line = "info depth 3 movesleft 42 nodes 100 ponderhit pv e2e4 e7e5";
+        assert!(matches!(
+            UciOut::from_line(line, Parsing::Strict),
+            Err(ProtocolError::UnexpectedToken)
+        ));
+        let Some(UciOut::Info {
+            depth,
+            nodes,
+            pv,
+            extra,
+            ..
+        }) = UciOut::from_line(line, Parsing::Lenient).unwrap()
+        else {
+            panic!("expected info");
+        };
+        assert_eq!(depth, Some(3));
+        assert_eq!(nodes, Some(100));
+        assert_eq!(pv.map(|pv| pv.len()), Some(2));
+        assert_eq!(extra.len(), 2);
+        assert_eq!(extra["movesleft"], "42");
+        assert_eq!(extra["ponderhit"], "");
+    }
+
+    #[test]
+    fn test_uci_in_round_trip() {
+        for line in [
+            "uci",
+            "isready",
+            "setoption name Clear Hash",
+            "setoption name Skill Level value 10",
+            "setoption name EvalFile value nn 1.nnue",
+            "ucinewgame",
+            "position startpos",
+            "position startpos moves e2e4 e7e5",
+            "position fen rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq - 0 1",
+            "position fen 8/8/8/8/8/8/4k3/4K3 w - - 0 1 moves e1d1",
+            "go searchmoves e2e4 d2d4 ponder wtime 1000 btime 2000 winc 10 binc 20 movestogo 5",
+            "go depth 20 nodes 1000000 mate 3 movetime 500 infinite",
+            "go",
+            "stop",
+            "ponderhit",
+        ] {
+            let uci = UciIn::from_line(line).unwrap().unwrap();
+            assert_eq!(uci.to_string(), line);
+        }
+
+        assert!(UciIn::from_line("quit").unwrap().is_none());
+        assert!(matches!(
+            UciIn::from_line("position fen invalid"),
+            Err(ProtocolError::InvalidFen(_))
+        ));
+        assert!(matches!(
+            UciIn::from_line("go depth"),
+            Err(ProtocolError::UnexpectedEndOfLine)
+        ));
+    }
 }
@@ -0,0 +1,2029 @@
+use std::{net::SocketAddr, time::Duration};
+
+use axum::{
+    body::{Body, Bytes, HttpBody},
+    http::{Request, StatusCode},
+    response::Response,
+    Router,
+};
+use futures::{SinkExt as _, StreamExt as _};
+use lila_engine::{
+    api::Work,
+    limits::Limits,
+    metrics_router,
+    model::ProviderSecret,
+    provider::{self, ProviderConfig},
+    rate_limit::RateLimit,
+    repo::{ExternalEngine, MemoryRepo},
+    router,
+    uci::Parsing,
+    AppState,
+};
+use serde_json::{json, Value};
+use tokio::{net::TcpStream, task, task::JoinHandle, time::sleep};
+use tokio_tungstenite::{connect_async, tungstenite::Message, MaybeTlsStream, WebSocketStream};
+use tower::ServiceExt as _;
+
+const ENGINE_ID: &str = "eei_aTKImBJOnv6j";
+const CLIENT_SECRET: &str = "ees_mdF2hK0hlKGSPeC6";
+const PROVIDER_SECRET: &str = "Dee3uwieZei9ahpaici9bee2yahsai0K";
+
+/// Engine of another user, on the same provider.
+const OTHER_ENGINE_ID: &str = "eei_Zu7chaiy3oof";
+const OTHER_CLIENT_SECRET: &str = "ees_ieNg6ohkoo1E";
+
+struct Harness {
+    state: AppState,
+    app: Router,
+    metrics: Router,
+}
+
+impl Harness {
+    fn new() -> Harness {
+        Harness::with_limits(Limits::default())
+    }
+
+    fn with_limits(limits: Limits) -> Harness {
+        let provider_secret: ProviderSecret =
+            serde_json::from_value(json!(PROVIDER_SECRET)).unwrap();
+        let engine = |id: &str, client_secret: &str, user_id: &str| ExternalEngine {
+            id: serde_json::from_value(json!(id)).unwrap(),
+            provider_selector: provider_secret.selector(),
+            config: serde_json::from_value(json!({
+                "name": "Stockfish 15",
+                "clientSecret": client_secret,
+                "userId": user_id,
+                "maxThreads": 8,
+                "maxHash": 2048,
+                "variants": ["chess", "antichess"],
+                "providerData": null,
+            }))
+            .unwrap(),
+        };
+        let repo = MemoryRepo::new([
+            engine(ENGINE_ID, CLIENT_SECRET, "revoof"),
+            engine(OTHER_ENGINE_ID, OTHER_CLIENT_SECRET, "other"),
+        ]);
+        let state = AppState::new(Box::leak(Box::new(repo)), limits);
+        Harness {
+            app: router(state.clone()),
+            metrics: metrics_router(state.clone()),
+            state,
+        }
+    }
+
+    fn request(&self, uri: &str, body: Body) -> JoinHandle<Response> {
+        self.send(
+            Request::post(uri)
+                .header("content-type", "application/json")
+                .body(body)
+                .unwrap(),
+        )
+    }
+
+    fn send(&self, req: Request<Body>) -> JoinHandle<Response> {
+        let app = self.app.clone();
+        task::spawn(async move { app.oneshot(req).await.unwrap() })
+    }
+
+    fn analyse(&self, work: Value) -> JoinHandle<Response> {
+        self.analyse_with_secret(CLIENT_SECRET, work)
+    }
+
+    fn analyse_with_secret(&self, client_secret: &str, work: Value) -> JoinHandle<Response> {
+        self.analyse_on(ENGINE_ID, client_secret, work)
+    }
+
+    fn analyse_on(
+        &self,
+        engine_id: &str,
+        client_secret: &str,
+        work: Value,
+    ) -> JoinHandle<Response> {
+        self.request(
+            &format!("/api/external-engine/{engine_id}/analyse"),
+            Body::from(json!({ "clientSecret": client_secret, "work": work }).to_string()),
+        )
+    }
+
+    fn cancel(&self, client_secret: &str, session_id: &str) -> JoinHandle<Response> {
+        self.request(
+            &format!("/api/external-engine/{ENGINE_ID}/cancel"),
+            Body::from(
+                json!({ "clientSecret": client_secret, "sessionId": session_id }).to_string(),
+            ),
+        )
+    }
+
+    fn acquire(&self) -> JoinHandle<Response> {
+        self.request(
+            "/api/external-engine/work",
+            Body::from(json!({ "providerSecret": PROVIDER_SECRET }).to_string()),
+        )
+    }
+
+    fn acquire_as(&self, instance: &str, max_threads: u32) -> JoinHandle<Response> {
+        self.request(
+            "/api/external-engine/work",
+            Body::from(
+                json!({
+                    "providerSecret": PROVIDER_SECRET,
+                    "instance": { "id": instance, "maxThreads": max_threads, "maxHash": 4096 },
+                })
+                .to_string(),
+            ),
+        )
+    }
+
+    fn submit(&self, id: &str) -> (FakeProvider, JoinHandle<Response>) {
+        let (sender, body) = Body::channel();
+        (
+            FakeProvider { sender },
+            self.request(&format!("/api/external-engine/work/{id}"), body),
+        )
+    }
+
+    async fn status(&self, id: &str) -> Response {
+        self.get(&format!("/api/external-engine/work/{id}/status"))
+            .await
+    }
+
+    fn spawn_garbage_collectors(&self) -> Vec<JoinHandle<()>> {
+        let state = self.state.clone();
+        vec![
+            task::spawn(state.hub.garbage_collect(state.limits.hub_gc_interval)),
+            task::spawn(
+                state
+                    .ongoing
+                    .garbage_collect(state.limits.ongoing_gc_interval),
+            ),
+            task::spawn(
+                state
+                    .sessions
+                    .garbage_collect(state.limits.ongoing_gc_interval),
+            ),
+            task::spawn(
+                state
+                    .progress
+                    .garbage_collect(state.limits.ongoing_gc_interval),
+            ),
+            task::spawn(
+                state
+                    .instances
+                    .garbage_collect(state.limits.ongoing_gc_interval),
+            ),
+        ]
+    }
+
+    fn selector(&self) -> String {
+        let provider_secret: ProviderSecret =
+            serde_json::from_value(json!(PROVIDER_SECRET)).unwrap();
+        provider_secret.selector().to_string()
+    }
+
+    async fn get(&self, uri: &str) -> Response {
+        self.send(Request::get(uri).body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+    }
+
+    /// Serves the app on a real local port.
+    fn serve(&self) -> SocketAddr {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = self.app.clone();
+        task::spawn(async move {
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service())
+                .await
+                .unwrap();
+        });
+        addr
+    }
+
+    async fn metrics(&self) -> String {
+        let req = Request::get("/metrics").body(Body::empty()).unwrap();
+        let res = self.metrics.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        String::from_utf8(read_body(res).await.to_vec()).unwrap()
+    }
+
+    /// Acquires the next job as a provider and starts streaming for it.
+    async fn pick_up(&self) -> (Value, FakeProvider, JoinHandle<Response>) {
+        let res = self.acquire().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let acquired = read_json(res).await;
+        let (provider, submitted) = self.submit(acquired["id"].as_str().unwrap());
+        (acquired, provider, submitted)
+    }
+}
+
+struct FakeProvider {
+    sender: hyper::body::Sender,
+}
+
+impl FakeProvider {
+    async fn send(&mut self, line: &str) {
+        self.sender
+            .send_data(Bytes::from(format!("{line}\n")))
+            .await
+            .unwrap();
+    }
+}
+
+/// Client or provider connected over a real WebSocket, since upgrades need
+/// an actual connection.
+struct SocketClient {
+    stream: WebSocketStream<MaybeTlsStream<TcpStream>>,
+}
+
+impl SocketClient {
+    async fn connect(harness: &Harness, path: &str, hello: Value) -> SocketClient {
+        let addr = harness.serve();
+        let (stream, res) = connect_async(format!("ws://{addr}{path}")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::SWITCHING_PROTOCOLS);
+        let mut client = SocketClient { stream };
+        client.send(hello).await;
+        client
+    }
+
+    async fn send(&mut self, message: Value) {
+        self.stream
+            .send(Message::Text(message.to_string()))
+            .await
+            .unwrap();
+    }
+
+    async fn line(&mut self, id: &str, line: &str) {
+        self.send(json!({ "id": id, "line": line })).await;
+    }
+
+    async fn recv(&mut self) -> Value {
+        match self.stream.next().await.unwrap().unwrap() {
+            Message::Text(text) => serde_json::from_str(&text).unwrap(),
+            message => panic!("unexpected {message:?}"),
+        }
+    }
+}
+
+struct EmitReader {
+    res: Response,
+    buf: Vec<u8>,
+}
+
+impl EmitReader {
+    fn new(res: Response) -> EmitReader {
+        assert_eq!(res.status(), StatusCode::OK);
+        EmitReader {
+            res,
+            buf: Vec::new(),
+        }
+    }
+
+    async fn next(&mut self) -> Option<Value> {
+        loop {
+            if let Some(pos) = self.buf.iter().position(|&b| b == b'\n') {
+                let line: Vec<u8> = self.buf.drain(..=pos).collect();
+                return Some(serde_json::from_slice(&line).unwrap());
+            }
+            match self.res.body_mut().data().await {
+                Some(chunk) => self.buf.extend_from_slice(&chunk.unwrap()),
+                None => {
+                    assert!(self.buf.is_empty(), "trailing partial line");
+                    return None;
+                }
+            }
+        }
+    }
+}
+
+async fn read_body(res: Response) -> Bytes {
+    hyper::body::to_bytes(res.into_body()).await.unwrap()
+}
+
+async fn read_json(res: Response) -> Value {
+    serde_json::from_slice(&read_body(res).await).unwrap()
+}
+
+fn work(multi_pv: u32) -> Value {
+    json!({
+        "sessionId": "sid_1",
+        "threads": 16,
+        "hash": 4096,
+        "movetime": 1000,
+        "multiPv": multi_pv,
+        "variant": "chess",
+        "initialFen": "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+        "moves": ["e2e4", "e7e5", "e1e2"],
+    })
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_analyse_acquire_submit() {
+    let harness = Harness::new();
+    let analysis = harness.analyse(work(2));
+
+    let (acquired, mut provider, submitted) = harness.pick_up().await;
+    assert_eq!(acquired["engine"]["id"], ENGINE_ID);
+    assert_eq!(acquired["engine"]["name"], "Stockfish 15");
+    assert_eq!(acquired["work"]["threads"], 8, "clamped to maxThreads");
+    assert_eq!(acquired["work"]["hash"], 2048, "clamped to maxHash");
+    assert_eq!(acquired["work"]["movetime"], 1000);
+    assert_eq!(acquired["work"]["moves"], json!(["e2e4", "e7e5", "e1e2"]));
+
+    let mut emits = EmitReader::new(analysis.await.unwrap());
+
+    provider
+        .send("info depth 1 seldepth 1 multipv 1 score cp 20 nodes 20 time 1 pv e8e7")
+        .await;
+    assert_eq!(
+        emits.next().await.unwrap(),
+        json!({
+            "time": 1,
+            "depth": 1,
+            "nodes": 20,
+            "pvs": [{ "moves": ["e8e7"], "cp": -20, "depth": 1 }],
+        })
+    );
+    provider
+        .send("info depth 1 seldepth 1 multipv 2 score cp 10 nodes 20 time 1 pv d7d5 e4d5")
+        .await;
+    assert_eq!(
+        emits.next().await.unwrap(),
+        json!({
+            "time": 1,
+            "depth": 1,
+            "nodes": 20,
+            "pvs": [
+                { "moves": ["e8e7"], "cp": -20, "depth": 1 },
+                { "moves": ["d7d5", "e4d5"], "cp": -10, "depth": 1 },
+            ],
+        })
+    );
+
+    provider
+        .send("info depth 2 multipv 1 score mate 3 lowerbound nodes 80 time 3 pv e8e7")
+        .await;
+    provider
+        .send("info depth 2 multipv 1 score mate 3 nodes 90 time 4 pv d8h4 e2e3 h4f4 e1e2")
+        .await;
+    provider
+        .send("info depth 2 multipv 2 score cp 15 nodes 95 time 4 pv d7d5 e4d5 d8d5 b1c3")
+        .await;
+    assert_eq!(
+        emits.next().await.unwrap(),
+        json!({
+            "time": 4,
+            "depth": 2,
+            "nodes": 90,
+            "pvs": [
+                { "moves": ["d8h4", "e2e3", "h4f4"], "mate": -3, "depth": 2 },
+                { "moves": ["d7d5", "e4d5", "d8d5", "b1c3"], "cp": -15, "depth": 2 },
+            ],
+        })
+    );
+
+    provider.send("bestmove d8h4 ponder e2e3").await;
+    assert_eq!(
+        emits.next().await.unwrap(),
+        json!({
+            "time": 4,
+            "depth": 2,
+            "nodes": 90,
+            "pvs": [
+                { "moves": ["d8h4", "e2e3", "h4f4"], "mate": -3, "depth": 2 },
+                { "moves": ["d7d5", "e4d5", "d8d5", "b1c3"], "cp": -15, "depth": 2 },
+            ],
+            "bestmove": "d8h4",
+            "ponder": "e2e3",
+        })
+    );
+    assert_eq!(emits.next().await, None);
+    assert_eq!(submitted.await.unwrap().status(), StatusCode::OK);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_provider_eof_ends_stream() {
+    let harness = Harness::new();
+    let analysis = harness.analyse(work(1));
+    let (_, mut provider, submitted) = harness.pick_up().await;
+    let mut emits = EmitReader::new(analysis.await.unwrap());
+
+    provider
+        .send("info depth 5 score cp -3 nodes 1000 time 10 pv d7d5")
+        .await;
+    assert_eq!(emits.next().await.unwrap()["depth"], 5);
+
+    drop(provider);
+    assert_eq!(emits.next().await, None);
+    assert_eq!(submitted.await.unwrap().status(), StatusCode::OK);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_provider_timeout() {
+    let harness = Harness::new();
+    let res = harness.analyse(work(1)).await.unwrap();
+    assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(read_body(res).await, "provider did not pick up work");
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_provider_acquired_but_never_submitted() {
+    let harness = Harness::new();
+    let analysis = harness.analyse(work(1));
+    assert_eq!(harness.acquire().await.unwrap().status(), StatusCode::OK);
+    let res = analysis.await.unwrap();
+    assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_acquire_timeout() {
+    let harness = Harness::new();
+    let res = harness.acquire().await.unwrap();
+    assert_eq!(res.status(), StatusCode::NO_CONTENT);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_cancelled_before_pickup() {
+    let harness = Harness::new();
+    let analysis = harness.analyse(work(1));
+    sleep(Duration::from_secs(1)).await;
+    analysis.abort();
+    assert!(analysis.await.unwrap_err().is_cancelled());
+
+    // The abandoned job is skipped, so the provider finds nothing to do.
+    let res = harness.acquire().await.unwrap();
+    assert_eq!(res.status(), StatusCode::NO_CONTENT);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_requester_gone_away() {
+    let harness = Harness::new();
+    let analysis = harness.analyse(work(1));
+    let (_, mut provider, submitted) = harness.pick_up().await;
+    let mut emits = EmitReader::new(analysis.await.unwrap());
+
+    provider
+        .send("info depth 1 score cp 20 nodes 20 time 1 pv e8e7")
+        .await;
+    assert!(emits.next().await.is_some());
+
+    drop(emits);
+    provider
+        .send("info depth 2 score cp 25 nodes 50 time 2 pv e8e7")
+        .await;
+    assert_eq!(submitted.await.unwrap().status(), StatusCode::OK);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_submit_unknown_work() {
+    let harness = Harness::new();
+    let (_, submitted) = harness.submit("unknownjobid1234");
+    let res = submitted.await.unwrap();
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_submit_protocol_error() {
+    let harness = Harness::new();
+    let analysis = harness.analyse(work(1));
+    let (_, mut provider, submitted) = harness.pick_up().await;
+    let mut emits = EmitReader::new(analysis.await.unwrap());
+
+    provider.send("info depth 1 bogus 2").await;
+    assert_eq!(submitted.await.unwrap().status(), StatusCode::BAD_REQUEST);
+    assert_eq!(emits.next().await, None);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_final_emit_incomplete_pvs() {
+    let harness = Harness::new();
+    let analysis = harness.analyse(work(2));
+    let (_, mut provider, submitted) = harness.pick_up().await;
+    let mut emits = EmitReader::new(analysis.await.unwrap());
+
+    provider
+        .send("info depth 1 multipv 1 score cp 20 nodes 20 time 1 pv e8e7")
+        .await;
+    provider
+        .send("info depth 1 multipv 2 score cp 10 nodes 20 time 1 pv d7d5")
+        .await;
+    emits.next().await.unwrap();
+    assert_eq!(
+        emits.next().await.unwrap()["pvs"].as_array().unwrap().len(),
+        2
+    );
+    provider
+        .send("info depth 2 multipv 1 score cp 30 nodes 40 time 2 pv e8e7")
+        .await;
+    provider.send("bestmove e8e7").await;
+    assert_eq!(
+        emits.next().await.unwrap(),
+        json!({
+            "time": 2,
+            "depth": 2,
+            "nodes": 40,
+            "pvs": [{ "moves": ["e8e7"], "cp": -30, "depth": 2 }],
+            "bestmove": "e8e7",
+        })
+    );
+    assert_eq!(submitted.await.unwrap().status(), StatusCode::OK);
+
+    // Without any complete pv, there are none at all.
+    let analysis = harness.analyse(session_work("sid_2"));
+    let (_, mut provider, submitted) = harness.pick_up().await;
+    let mut emits = EmitReader::new(analysis.await.unwrap());
+    provider.send("bestmove e8e7").await;
+    assert_eq!(
+        emits.next().await.unwrap(),
+        json!({ "time": 0, "depth": 0, "nodes": 0, "bestmove": "e8e7" })
+    );
+    assert_eq!(submitted.await.unwrap().status(), StatusCode::OK);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_unrequested_multi_pv() {
+    let harness = Harness::new();
+    let analysis = harness.analyse(work(1));
+    let (_, mut provider, submitted) = harness.pick_up().await;
+    let mut emits = EmitReader::new(analysis.await.unwrap());
+
+    provider
+        .send("info depth 1 multipv 1 score cp 20 nodes 20 time 1 pv e8e7")
+        .await;
+    assert_eq!(emits.next().await.unwrap()["depth"], 1);
+    provider
+        .send("info depth 1 multipv 300 score cp 10 nodes 20 time 1 pv d7d5")
+        .await;
+    provider
+        .send("info depth 2 multipv 1 score cp 30 nodes 40 time 2 pv e8e7")
+        .await;
+    assert_eq!(
+        emits.next().await.unwrap()["pvs"],
+        json!([{ "moves": ["e8e7"], "cp": -30, "depth": 2 }])
+    );
+    provider.send("bestmove e8e7").await;
+    assert_eq!(
+        emits.next().await.unwrap()["pvs"].as_array().unwrap().len(),
+        1
+    );
+    assert_eq!(submitted.await.unwrap().status(), StatusCode::OK);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_lenient_parsing() {
+    let harness = Harness::with_limits(Limits {
+        uci_parsing: Parsing::Lenient,
+        ..Limits::default()
+    });
+    let analysis = harness.analyse(work(1));
+    let (_, mut provider, submitted) = harness.pick_up().await;
+    let mut emits = EmitReader::new(analysis.await.unwrap());
+
+    provider
+        .send("info depth 1 score cp 20 movesleft 42 nodes 20 time 1 pv e8e7")
+        .await;
+    assert_eq!(
+        emits.next().await.unwrap()["pvs"],
+        json!([{ "moves": ["e8e7"], "cp": -20, "depth": 1 }])
+    );
+    provider.send("bestmove e8e7").await;
+    assert_eq!(emits.next().await.unwrap()["bestmove"], "e8e7");
+    assert_eq!(submitted.await.unwrap().status(), StatusCode::OK);
+    assert!(harness
+        .metrics()
+        .await
+        .contains("lila_engine_lenient_lines_total 1\n"));
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_engine_not_found() {
+    let harness = Harness::new();
+    let res = harness
+        .analyse_with_secret("ees_wrong", work(1))
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_invalid_work() {
+    let harness = Harness::new();
+
+    let mut unsupported = work(1);
+    unsupported["variant"] = json!("atomic");
+    let res = harness.analyse(unsupported).await.unwrap();
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(read_body(res).await, "invalid work: unsupported variant");
+
+    let mut illegal = work(1);
+    illegal["moves"] = json!(["e2e5"]);
+    let res = harness.analyse(illegal).await.unwrap();
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_cancel_before_pickup() {
+    let harness = Harness::new();
+    let analysis = harness.analyse(work(1));
+    sleep(Duration::from_secs(1)).await;
+
+    let res = harness.cancel(CLIENT_SECRET, "sid_1").await.unwrap();
+    assert_eq!(res.status(), StatusCode::NO_CONTENT);
+
+    let res = analysis.await.unwrap();
+    assert_eq!(res.status(), StatusCode::GONE);
+    assert_eq!(read_body(res).await, "work cancelled");
+
+    let res = harness.acquire().await.unwrap();
+    assert_eq!(res.status(), StatusCode::NO_CONTENT);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_cancel_after_acquire() {
+    let harness = Harness::new();
+    let analysis = harness.analyse(work(1));
+    let res = harness.acquire().await.unwrap();
+    let acquired = read_json(res).await;
+
+    let res = harness.cancel(CLIENT_SECRET, "sid_1").await.unwrap();
+    assert_eq!(res.status(), StatusCode::NO_CONTENT);
+    assert_eq!(analysis.await.unwrap().status(), StatusCode::GONE);
+
+    let (_, submitted) = harness.submit(acquired["id"].as_str().unwrap());
+    assert_eq!(submitted.await.unwrap().status(), StatusCode::GONE);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_cancel_while_streaming() {
+    let harness = Harness::new();
+    let analysis = harness.analyse(work(1));
+    let (_, mut provider, submitted) = harness.pick_up().await;
+    let mut emits = EmitReader::new(analysis.await.unwrap());
+
+    provider
+        .send("info depth 1 score cp 20 nodes 20 time 1 pv e8e7")
+        .await;
+    assert!(emits.next().await.is_some());
+
+    let res = harness.cancel(CLIENT_SECRET, "sid_1").await.unwrap();
+    assert_eq!(res.status(), StatusCode::NO_CONTENT);
+
+    let res = submitted.await.unwrap();
+    assert_eq!(res.status(), StatusCode::GONE);
+    assert_eq!(read_body(res).await, "work cancelled");
+    assert_eq!(emits.next().await, None);
+
+    // Nothing left to cancel.
+    let res = harness.cancel(CLIENT_SECRET, "sid_1").await.unwrap();
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_cancel_requires_client_secret() {
+    let harness = Harness::new();
+    let analysis = harness.analyse(work(1));
+    sleep(Duration::from_secs(1)).await;
+
+    let res = harness.cancel("ees_wrong", "sid_1").await.unwrap();
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    let res = harness.cancel(CLIENT_SECRET, "sid_other").await.unwrap();
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+    let (_, mut provider, submitted) = harness.pick_up().await;
+    let mut emits = EmitReader::new(analysis.await.unwrap());
+    provider.send("bestmove e8e7").await;
+    assert_eq!(emits.next().await.unwrap()["bestmove"], "e8e7");
+    assert_eq!(emits.next().await, None);
+    assert_eq!(submitted.await.unwrap().status(), StatusCode::OK);
+}
+
+fn job_id(res: &Response) -> String {
+    res.headers()["x-job-id"].to_str().unwrap().to_owned()
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_status() {
+    let harness = Harness::new();
+    let analysis = harness.analyse(work(1));
+    let (acquired, mut provider, submitted) = harness.pick_up().await;
+    let res = analysis.await.unwrap();
+    let id = job_id(&res);
+    assert_eq!(acquired["id"], id.as_str());
+    let mut emits = EmitReader::new(res);
+
+    provider
+        .send("info depth 1 score cp 20 nodes 20 time 1 pv e8e7")
+        .await;
+    assert!(emits.next().await.is_some());
+    let status = read_json(harness.status(&id).await).await;
+    assert_eq!(status["status"], "streaming");
+    assert_eq!(status["emit"]["depth"], 1);
+
+    provider.send("bestmove e8e7").await;
+    assert_eq!(emits.next().await.unwrap()["bestmove"], "e8e7");
+    assert_eq!(emits.next().await, None);
+    assert_eq!(submitted.await.unwrap().status(), StatusCode::OK);
+    let status = read_json(harness.status(&id).await).await;
+    assert_eq!(status["status"], "finished");
+    assert_eq!(status["emit"]["depth"], 1);
+
+    // Expired.
+    sleep(Duration::from_secs(61)).await;
+    let res = harness.status(&id).await;
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_status_unknown_work() {
+    let harness = Harness::new();
+    let res = harness.status("unknownjobid1234").await;
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_resume_after_requester_gone_away() {
+    let harness = Harness::new();
+    let analysis = harness.analyse(work(1));
+    let (_, mut provider, submitted) = harness.pick_up().await;
+    let res = analysis.await.unwrap();
+    let id = job_id(&res);
+    let mut emits = EmitReader::new(res);
+
+    provider
+        .send("info depth 7 score cp 20 nodes 20 time 1 pv e8e7")
+        .await;
+    assert!(emits.next().await.is_some());
+    drop(emits);
+    provider
+        .send("info depth 8 score cp 25 nodes 50 time 2 pv e8e7")
+        .await;
+    assert_eq!(submitted.await.unwrap().status(), StatusCode::OK);
+
+    // The search was stopped early, so the same work is searched again.
+    let analysis = harness.analyse(work(1));
+    let (acquired, mut provider, submitted) = harness.pick_up().await;
+    assert_ne!(acquired["id"], id.as_str());
+    let id = acquired["id"].as_str().unwrap().to_owned();
+    let mut emits = EmitReader::new(analysis.await.unwrap());
+    provider
+        .send("info depth 9 score cp 25 nodes 50 time 2 pv e8e7")
+        .await;
+    provider.send("bestmove e8e7").await;
+    assert_eq!(emits.next().await.unwrap()["depth"], 9);
+    assert_eq!(emits.next().await.unwrap()["bestmove"], "e8e7");
+    assert_eq!(emits.next().await, None);
+    assert_eq!(submitted.await.unwrap().status(), StatusCode::OK);
+
+    // Same session and work of a complete search: replays the result.
+    let res = harness.analyse(work(1)).await.unwrap();
+    assert_eq!(job_id(&res), id);
+    let mut emits = EmitReader::new(res);
+    let emit = emits.next().await.unwrap();
+    assert_eq!(emit["depth"], 9);
+    assert_eq!(emit["bestmove"], "e8e7");
+    assert_eq!(emits.next().await, None);
+
+    // Different work in the same session starts a new search.
+    let analysis = harness.analyse(work(2));
+    let (acquired, _, _) = harness.pick_up().await;
+    assert_ne!(acquired["id"], id.as_str());
+    drop(analysis);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_resume_while_streaming() {
+    let harness = Harness::new();
+    let analysis = harness.analyse(work(1));
+    let (_, mut provider, submitted) = harness.pick_up().await;
+    let mut first = EmitReader::new(analysis.await.unwrap());
+
+    provider
+        .send("info depth 1 score cp 20 nodes 20 time 1 pv e8e7")
+        .await;
+    assert_eq!(first.next().await.unwrap()["depth"], 1);
+
+    let mut second = EmitReader::new(harness.analyse(work(1)).await.unwrap());
+    assert_eq!(second.next().await.unwrap()["depth"], 1);
+
+    provider
+        .send("info depth 2 score cp 25 nodes 50 time 2 pv e8e7")
+        .await;
+    assert_eq!(first.next().await.unwrap()["depth"], 2);
+    assert_eq!(second.next().await.unwrap()["depth"], 2);
+
+    provider.send("bestmove e8e7").await;
+    assert_eq!(first.next().await.unwrap()["bestmove"], "e8e7");
+    assert_eq!(second.next().await.unwrap()["bestmove"], "e8e7");
+    assert_eq!(first.next().await, None);
+    assert_eq!(second.next().await, None);
+    assert_eq!(submitted.await.unwrap().status(), StatusCode::OK);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_supersede_queued() {
+    let harness = Harness::new();
+    let first = harness.analyse(work(1));
+    sleep(Duration::from_secs(1)).await;
+    let second = harness.analyse(work(2));
+    sleep(Duration::from_secs(1)).await;
+
+    let res = first.await.unwrap();
+    assert_eq!(res.status(), StatusCode::GONE);
+
+    // Only the latest position reaches the provider.
+    let (acquired, mut provider, submitted) = harness.pick_up().await;
+    assert_eq!(acquired["work"]["multiPv"], 2);
+    let mut emits = EmitReader::new(second.await.unwrap());
+    provider.send("bestmove e8e7").await;
+    assert_eq!(emits.next().await.unwrap()["bestmove"], "e8e7");
+    assert_eq!(emits.next().await, None);
+    assert_eq!(submitted.await.unwrap().status(), StatusCode::OK);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_supersede_streaming() {
+    let harness = Harness::new();
+    let first = harness.analyse(work(1));
+    let (_, mut provider, submitted) = harness.pick_up().await;
+    let mut emits = EmitReader::new(first.await.unwrap());
+    provider
+        .send("info depth 1 score cp 20 nodes 20 time 1 pv e8e7")
+        .await;
+    assert!(emits.next().await.is_some());
+
+    let mut next = work(1);
+    next["moves"] = json!(["e2e4", "e7e5", "e1e2", "d8h4"]);
+    let second = harness.analyse(next);
+
+    let res = submitted.await.unwrap();
+    assert_eq!(res.status(), StatusCode::GONE);
+    assert_eq!(read_body(res).await, "work cancelled");
+    assert_eq!(emits.next().await, None);
+
+    let (acquired, _, _) = harness.pick_up().await;
+    assert_eq!(acquired["work"]["moves"].as_array().unwrap().len(), 4);
+    drop(second);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_other_session_not_superseded() {
+    let harness = Harness::new();
+    let first = harness.analyse(work(1));
+    let (_, mut provider, submitted) = harness.pick_up().await;
+    let mut emits = EmitReader::new(first.await.unwrap());
+
+    let mut other = work(1);
+    other["sessionId"] = json!("sid_2");
+    let second = harness.analyse(other);
+    sleep(Duration::from_secs(1)).await;
+
+    provider
+        .send("info depth 1 score cp 20 nodes 20 time 1 pv e8e7")
+        .await;
+    assert!(emits.next().await.is_some());
+    provider.send("bestmove e8e7").await;
+    assert_eq!(emits.next().await.unwrap()["bestmove"], "e8e7");
+    assert_eq!(emits.next().await, None);
+    assert_eq!(submitted.await.unwrap().status(), StatusCode::OK);
+    drop(second);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_metrics() {
+    let harness = Harness::new();
+    let analysis = harness.analyse(work(1));
+    sleep(Duration::from_secs(1)).await;
+    let metrics = harness.metrics().await;
+    assert!(metrics.contains("# TYPE lila_engine_hub_queue_depth gauge\n"));
+    assert!(metrics.contains("lila_engine_analyse_requests_total 1\n"));
+    assert!(metrics.contains(&format!(
+        "lila_engine_hub_queue_depth{{selector=\"{}\"}} 1\n",
+        harness.selector()
+    )));
+
+    let (_, mut provider, submitted) = harness.pick_up().await;
+    let mut emits = EmitReader::new(analysis.await.unwrap());
+    provider
+        .send("info depth 1 score cp 20 nodes 20 time 1 pv e8e7")
+        .await;
+    assert!(emits.next().await.is_some());
+    provider.send("bestmove e8e7").await;
+    assert_eq!(emits.next().await.unwrap()["bestmove"], "e8e7");
+    assert_eq!(emits.next().await, None);
+    assert_eq!(submitted.await.unwrap().status(), StatusCode::OK);
+
+    assert_eq!(
+        harness.acquire().await.unwrap().status(),
+        StatusCode::NO_CONTENT
+    );
+
+    let metrics = harness.metrics().await;
+    assert!(!metrics.contains("lila_engine_hub_queue_depth{"));
+    assert!(metrics.contains("lila_engine_acquire_requests_total 2\n"));
+    assert!(metrics.contains("lila_engine_acquire_timeouts_total 1\n"));
+    assert!(metrics.contains("lila_engine_provider_lines_total 2\n"));
+    assert!(metrics.contains("lila_engine_emitted_lines_total 2\n"));
+    assert!(metrics.contains("lila_engine_ongoing_jobs 0\n"));
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_health() {
+    let harness = Harness::new();
+    let res = harness.get("/health").await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(read_body(res).await, "ok");
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_ready() {
+    let harness = Harness::new();
+
+    // Garbage collectors are not running yet.
+    let res = harness.get("/ready").await;
+    assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+    let ready = read_json(res).await;
+    assert_eq!(ready["ready"], false);
+    assert_eq!(ready["hub"]["sinceGarbageCollection"], Value::Null);
+
+    let collectors = harness.spawn_garbage_collectors();
+    let analysis = harness.analyse(work(1));
+    sleep(Duration::from_secs(1)).await;
+    let res = harness.get("/ready").await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let ready = read_json(res).await;
+    assert_eq!(ready["ready"], true);
+    assert_eq!(ready["hub"]["shards"], 64);
+    assert_eq!(ready["hub"]["entries"], 1);
+    assert_eq!(ready["hub"]["maxShardEntries"], 1);
+    assert_eq!(ready["sessions"]["entries"], 1);
+    assert!(ready.get("registryError").is_none());
+
+    // Collectors gone.
+    for collector in collectors {
+        collector.abort();
+    }
+    drop(analysis);
+    sleep(Duration::from_secs(61)).await;
+    let res = harness.get("/ready").await;
+    assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_drain() {
+    let harness = Harness::new();
+    let analysis = harness.analyse(work(1));
+    let (_, mut provider, submitted) = harness.pick_up().await;
+    let mut emits = EmitReader::new(analysis.await.unwrap());
+
+    let mut other = work(1);
+    other["sessionId"] = json!("sid_2");
+    let queued = harness.analyse(other);
+    sleep(Duration::from_secs(1)).await;
+    harness.state.drain();
+
+    // Queued and new requests are turned away.
+    let res = queued.await.unwrap();
+    assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(read_body(res).await, "shutting down");
+    let res = harness.analyse(work(2)).await.unwrap();
+    assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+    let res = harness.acquire().await.unwrap();
+    assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+    let res = harness.get("/ready").await;
+    assert_eq!(read_json(res).await["draining"], true);
+
+    // Ongoing analysis runs to completion.
+    provider
+        .send("info depth 1 score cp 20 nodes 20 time 1 pv e8e7")
+        .await;
+    assert!(emits.next().await.is_some());
+    provider.send("bestmove e8e7").await;
+    assert_eq!(emits.next().await.unwrap()["bestmove"], "e8e7");
+    assert_eq!(emits.next().await, None);
+    assert_eq!(submitted.await.unwrap().status(), StatusCode::OK);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_drain_acquired() {
+    let harness = Harness::new();
+    let analysis = harness.analyse(work(1));
+    let res = harness.acquire().await.unwrap();
+    let id = read_json(res).await["id"].as_str().unwrap().to_owned();
+    harness.state.drain();
+
+    // Work acquired before draining can still be submitted.
+    assert!(!harness.state.is_drained());
+    let (mut provider, submitted) = harness.submit(&id);
+    let mut emits = EmitReader::new(analysis.await.unwrap());
+    assert!(harness.state.is_drained());
+    provider.send("bestmove e8e7").await;
+    assert_eq!(emits.next().await.unwrap()["bestmove"], "e8e7");
+    assert_eq!(submitted.await.unwrap().status(), StatusCode::OK);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_drain_pending_acquire() {
+    let harness = Harness::new();
+    let acquire = harness.acquire();
+    sleep(Duration::from_secs(1)).await;
+    harness.state.drain();
+    let res = acquire.await.unwrap();
+    assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_custom_limits() {
+    let harness = Harness::with_limits(Limits {
+        provider_timeout: Duration::from_secs(3),
+        max_moves: 2,
+        max_pv_moves: 1,
+        max_multi_pv: 1.try_into().unwrap(),
+        ..Limits::default()
+    });
+
+    let res = harness.analyse(work(2)).await.unwrap();
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(
+        read_body(res).await,
+        "invalid work: multiPv must be at most 1"
+    );
+
+    let res = harness.analyse(work(1)).await.unwrap();
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(read_body(res).await, "invalid work: too many moves");
+
+    let mut short = work(1);
+    short["moves"] = json!(["e2e4", "e7e5"]);
+    let analysis = harness.analyse(short.clone());
+    sleep(Duration::from_secs(4)).await;
+    assert_eq!(
+        analysis.await.unwrap().status(),
+        StatusCode::SERVICE_UNAVAILABLE
+    );
+
+    let analysis = harness.analyse(short);
+    let (_, mut provider, submitted) = harness.pick_up().await;
+    let mut emits = EmitReader::new(analysis.await.unwrap());
+    provider
+        .send("info depth 3 score cp 20 nodes 20 time 1 pv g1f3 b8c6 f1b5")
+        .await;
+    assert_eq!(
+        emits.next().await.unwrap()["pvs"][0]["moves"],
+        json!(["g1f3"])
+    );
+    provider.send("bestmove g1f3").await;
+    assert_eq!(emits.next().await.unwrap()["bestmove"], "g1f3");
+    assert_eq!(emits.next().await, None);
+    assert_eq!(submitted.await.unwrap().status(), StatusCode::OK);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_rate_limit() {
+    let harness = Harness::with_limits(Limits {
+        engine_rate: RateLimit {
+            burst: 2,
+            per_minute: 4,
+        },
+        ..Limits::default()
+    });
+
+    let mut invalid = work(1);
+    invalid["variant"] = json!("atomic");
+    for _ in 0..2 {
+        let res = harness.analyse(invalid.clone()).await.unwrap();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    }
+    let res = harness.analyse(invalid.clone()).await.unwrap();
+    assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(res.headers()["retry-after"], "15");
+    assert_eq!(read_body(res).await, "too many requests, retry in 15s");
+
+    sleep(Duration::from_secs(15)).await;
+    let res = harness.analyse(invalid).await.unwrap();
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_rate_limit_per_user() {
+    let harness = Harness::with_limits(Limits {
+        user_rate: RateLimit {
+            burst: 1,
+            per_minute: 1,
+        },
+        ..Limits::default()
+    });
+
+    let mut invalid = work(1);
+    invalid["variant"] = json!("atomic");
+    let res = harness.analyse(invalid.clone()).await.unwrap();
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    let res = harness.analyse(invalid).await.unwrap();
+    assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(res.headers()["retry-after"], "60");
+
+    // Unknown engines are not charged.
+    let res = harness
+        .analyse_with_secret("ees_wrong", work(1))
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+}
+
+fn session_work(session_id: &str) -> Value {
+    let mut work = work(1);
+    work["sessionId"] = json!(session_id);
+    work
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_queue_full_rejects() {
+    let harness = Harness::with_limits(Limits {
+        max_queued: 2,
+        ..Limits::default()
+    });
+    let first = harness.analyse(session_work("sid_1"));
+    let second = harness.analyse(session_work("sid_2"));
+    sleep(Duration::from_secs(1)).await;
+
+    let res = harness.analyse(session_work("sid_3")).await.unwrap();
+    assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(read_body(res).await, "provider busy");
+
+    // Abandoned jobs make room.
+    first.abort();
+    sleep(Duration::from_secs(1)).await;
+    let third = harness.analyse(session_work("sid_3"));
+    sleep(Duration::from_secs(1)).await;
+
+    let (acquired, _, _) = harness.pick_up().await;
+    assert_eq!(acquired["work"]["sessionId"], "sid_2");
+    let (acquired, _, _) = harness.pick_up().await;
+    assert_eq!(acquired["work"]["sessionId"], "sid_3");
+    drop((second, third));
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_queue_full_replaces_same_session() {
+    let harness = Harness::with_limits(Limits {
+        max_queued: 2,
+        ..Limits::default()
+    });
+    let first = harness.analyse(session_work("sid_1"));
+    let second = harness.analyse(session_work("sid_2"));
+    sleep(Duration::from_secs(1)).await;
+
+    // Jobs of other sessions are not replaced.
+    let res = harness.analyse(session_work("sid_3")).await.unwrap();
+    assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(read_body(res).await, "provider busy");
+
+    // New work of a queued session supersedes the old work, which makes
+    // room for it.
+    let mut newer = session_work("sid_1");
+    newer["multiPv"] = json!(2);
+    let third = harness.analyse(newer);
+    assert_eq!(first.await.unwrap().status(), StatusCode::GONE);
+
+    let (acquired, _, _) = harness.pick_up().await;
+    assert_eq!(acquired["work"]["sessionId"], "sid_2");
+    let (acquired, _, _) = harness.pick_up().await;
+    assert_eq!(acquired["work"]["sessionId"], "sid_1");
+    assert_eq!(acquired["work"]["multiPv"], 2);
+    drop((second, third));
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_fair_scheduling() {
+    let harness = Harness::new();
+    let mut pending = Vec::new();
+    for sid in ["sid_1", "sid_2", "sid_3"] {
+        let mut work = session_work(sid);
+        work["priority"] = json!("batch");
+        pending.push(harness.analyse(work));
+        sleep(Duration::from_millis(10)).await;
+    }
+    let mut other = session_work("sid_4");
+    other["priority"] = json!("batch");
+    pending.push(harness.analyse_on(OTHER_ENGINE_ID, OTHER_CLIENT_SECRET, other));
+    sleep(Duration::from_millis(10)).await;
+    pending.push(harness.analyse(session_work("sid_5")));
+    sleep(Duration::from_millis(10)).await;
+
+    let mut order = Vec::new();
+    for _ in 0..5 {
+        let (acquired, _, _) = harness.pick_up().await;
+        order.push(acquired["work"]["sessionId"].as_str().unwrap().to_owned());
+    }
+    assert_eq!(order, ["sid_5", "sid_1", "sid_4", "sid_2", "sid_3"]);
+    drop(pending);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_requeue_to_other_instance() {
+    let harness = Harness::new();
+    let analysis = harness.analyse(work(1));
+
+    // First instance acquires, but never submits.
+    let res = harness.acquire_as("a", 16).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let id = read_json(res).await["id"].as_str().unwrap().to_owned();
+    let again = harness.acquire_as("a", 16);
+    let other = harness.acquire_as("b", 16);
+    sleep(Duration::from_secs(6)).await;
+
+    // Job is handed to the other instance.
+    let res = other.await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(read_json(res).await["id"], id.as_str());
+    let (mut provider, submitted) = harness.submit(&id);
+    assert_eq!(again.await.unwrap().status(), StatusCode::NO_CONTENT);
+
+    let res = analysis.await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(job_id(&res), id);
+    let mut emits = EmitReader::new(res);
+    provider
+        .send("info depth 1 score cp 20 nodes 20 time 1 pv e8e7")
+        .await;
+    assert_eq!(emits.next().await.unwrap()["depth"], 1);
+    provider.send("bestmove e8e7").await;
+    assert_eq!(submitted.await.unwrap().status(), StatusCode::OK);
+
+    let metrics = harness.metrics().await;
+    assert!(metrics.contains("lila_engine_jobs_requeued_total 1\n"));
+    assert!(metrics.contains("lila_engine_provider_instances 1\n"));
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_requeue_to_same_instance() {
+    let harness = Harness::new();
+    let analysis = harness.analyse(work(1));
+
+    // The only instance stalls after acquiring.
+    let res = harness.acquire_as("a", 16).await.unwrap();
+    let id = read_json(res).await["id"].as_str().unwrap().to_owned();
+    let again = harness.acquire_as("a", 16);
+    sleep(Duration::from_secs(6)).await;
+
+    // No other instance could take the job, so it gets it again.
+    let res = again.await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(read_json(res).await["id"], id.as_str());
+    let (mut provider, submitted) = harness.submit(&id);
+    let mut emits = EmitReader::new(analysis.await.unwrap());
+    provider.send("bestmove e8e7").await;
+    assert_eq!(emits.next().await.unwrap()["bestmove"], "e8e7");
+    assert_eq!(submitted.await.unwrap().status(), StatusCode::OK);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_instance_capacity() {
+    let harness = Harness::new();
+    let small = harness.acquire_as("small", 4);
+    let analysis = harness.analyse(work(1));
+    sleep(Duration::from_secs(1)).await;
+
+    let res = harness.acquire_as("large", 8).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(read_json(res).await["work"]["threads"], 8);
+    assert_eq!(small.await.unwrap().status(), StatusCode::NO_CONTENT);
+    drop(analysis);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_lease_expires() {
+    let harness = Harness::with_limits(Limits {
+        max_requeues: 1,
+        ..Limits::default()
+    });
+    let analysis = harness.analyse(work(1));
+
+    // Acquired twice, but never submitted.
+    let mut ids = Vec::new();
+    for _ in 0..2 {
+        let res = harness.acquire().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        ids.push(read_json(res).await["id"].as_str().unwrap().to_owned());
+        let status = read_json(harness.status(&ids[0]).await).await;
+        assert_eq!(status["status"], "acquired");
+        sleep(Duration::from_secs(6)).await;
+    }
+    assert_eq!(ids[0], ids[1]);
+
+    let res = analysis.await.unwrap();
+    assert_eq!(res.status(), StatusCode::BAD_GATEWAY);
+    assert_eq!(
+        read_body(res).await,
+        "provider acquired work but never submitted it"
+    );
+    let status = read_json(harness.status(&ids[0]).await).await;
+    assert_eq!(status["status"], "failed");
+    let (_, submitted) = harness.submit(&ids[0]);
+    assert_eq!(submitted.await.unwrap().status(), StatusCode::NOT_FOUND);
+
+    let metrics = harness.metrics().await;
+    assert!(metrics.contains("lila_engine_jobs_requeued_total 1\n"));
+    assert!(metrics.contains("lila_engine_jobs_failed_total 1\n"));
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_lease_expires_with_default_limits() {
+    assert!(Limits::default().validate().is_ok());
+    let harness = Harness::new();
+    let analysis = harness.analyse(work(1));
+
+    // Every attempt expires before the client gives up.
+    for _ in 0..3 {
+        let res = harness.acquire().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        sleep(Duration::from_secs(5)).await;
+    }
+
+    let res = analysis.await.unwrap();
+    assert_eq!(res.status(), StatusCode::BAD_GATEWAY);
+    assert!(harness
+        .metrics()
+        .await
+        .contains("lila_engine_jobs_failed_total 1\n"));
+}
+
+#[test]
+fn test_inconsistent_limits() {
+    let limits = Limits {
+        submit_timeout: Duration::from_secs(5),
+        ..Limits::default()
+    };
+    assert!(limits.validate().is_err());
+}
+
+#[tokio::test]
+async fn test_socket() {
+    let harness = Harness::new();
+    let mut provider = SocketClient::connect(
+        &harness,
+        "/api/external-engine/work/socket",
+        json!({ "providerSecret": PROVIDER_SECRET, "concurrency": 2 }),
+    )
+    .await;
+
+    let first = harness.analyse(session_work("sid_1"));
+    let pushed = provider.recv().await;
+    assert_eq!(pushed["type"], "work");
+    assert_eq!(pushed["engine"]["id"], ENGINE_ID);
+    assert_eq!(pushed["work"]["sessionId"], "sid_1");
+    let first_id = pushed["id"].as_str().unwrap().to_owned();
+
+    let second = harness.analyse(session_work("sid_2"));
+    let pushed = provider.recv().await;
+    assert_eq!(pushed["work"]["sessionId"], "sid_2");
+    let second_id = pushed["id"].as_str().unwrap().to_owned();
+
+    // Lines are multiplexed by job. Clients get a response once the
+    // provider starts streaming.
+    provider
+        .line(
+            &second_id,
+            "info depth 2 score cp 10 nodes 20 time 1 pv e8e7",
+        )
+        .await;
+    provider
+        .line(
+            &first_id,
+            "info depth 1 score cp 20 nodes 20 time 1 pv e8e7",
+        )
+        .await;
+    let mut first = EmitReader::new(first.await.unwrap());
+    let mut second = EmitReader::new(second.await.unwrap());
+    assert_eq!(first.next().await.unwrap()["depth"], 1);
+    assert_eq!(second.next().await.unwrap()["depth"], 2);
+
+    provider.line(&first_id, "bestmove e8e7").await;
+    assert_eq!(first.next().await.unwrap()["bestmove"], "e8e7");
+    assert_eq!(first.next().await, None);
+    assert_eq!(
+        provider.recv().await,
+        json!({ "type": "stop", "id": first_id })
+    );
+
+    // Cancelled work is stopped.
+    let res = harness.cancel(CLIENT_SECRET, "sid_2").await.unwrap();
+    assert_eq!(res.status(), StatusCode::NO_CONTENT);
+    assert_eq!(
+        provider.recv().await,
+        json!({ "type": "stop", "id": second_id })
+    );
+    assert_eq!(second.next().await, None);
+
+    assert!(harness
+        .metrics()
+        .await
+        .contains("lila_engine_socket_jobs_total 2\n"));
+}
+
+#[tokio::test]
+async fn test_socket_slow_client() {
+    let harness = Harness::new();
+    let mut provider = SocketClient::connect(
+        &harness,
+        "/api/external-engine/work/socket",
+        json!({ "providerSecret": PROVIDER_SECRET }),
+    )
+    .await;
+
+    let analysis = harness.analyse(work(1));
+    let id = provider.recv().await["id"].as_str().unwrap().to_owned();
+
+    // The client does not read while the provider floods the socket.
+    for depth in 1..=100 {
+        provider
+            .line(
+                &id,
+                &format!("info depth {depth} score cp 20 nodes 20 time 1 pv e8e7"),
+            )
+            .await;
+    }
+    provider.line(&id, "bestmove e8e7").await;
+    let mut emits = EmitReader::new(analysis.await.unwrap());
+
+    let mut last = None;
+    while let Some(emit) = emits.next().await {
+        last = Some(emit);
+    }
+    let last = last.unwrap();
+    assert_eq!(last["bestmove"], "e8e7");
+    assert!(last["depth"].as_u64().unwrap() < 100);
+    assert!(!harness
+        .metrics()
+        .await
+        .contains("lila_engine_socket_lines_dropped_total 0\n"));
+}
+
+#[tokio::test]
+async fn test_socket_disconnect() {
+    let harness = Harness::new();
+    let mut provider = SocketClient::connect(
+        &harness,
+        "/api/external-engine/work/socket",
+        json!({ "providerSecret": PROVIDER_SECRET, "concurrency": 2 }),
+    )
+    .await;
+
+    let streaming = harness.analyse(session_work("sid_1"));
+    let streaming_id = provider.recv().await["id"].as_str().unwrap().to_owned();
+    provider
+        .line(
+            &streaming_id,
+            "info depth 1 score cp 20 nodes 20 time 1 pv e8e7",
+        )
+        .await;
+    let mut streaming = EmitReader::new(streaming.await.unwrap());
+    assert_eq!(streaming.next().await.unwrap()["depth"], 1);
+
+    let pending = harness.analyse(session_work("sid_2"));
+    let pending_id = provider.recv().await["id"].as_str().unwrap().to_owned();
+
+    drop(provider);
+
+    // The client of the job in progress learns that it failed.
+    assert!(matches!(
+        streaming.res.body_mut().data().await,
+        Some(Err(_))
+    ));
+    let status = read_json(
+        harness
+            .get(&format!("/api/external-engine/work/{streaming_id}/status"))
+            .await,
+    )
+    .await;
+    assert_eq!(status["status"], "failed");
+
+    // The job that was not started yet goes to another provider.
+    let (acquired, mut other, submitted) = harness.pick_up().await;
+    assert_eq!(acquired["id"], pending_id.as_str());
+    let mut pending = EmitReader::new(pending.await.unwrap());
+    other.send("bestmove e8e7").await;
+    assert_eq!(pending.next().await.unwrap()["bestmove"], "e8e7");
+    assert_eq!(submitted.await.unwrap().status(), StatusCode::OK);
+    assert!(harness
+        .metrics()
+        .await
+        .contains("lila_engine_jobs_requeued_total 1\n"));
+}
+
+#[tokio::test]
+async fn test_socket_requires_upgrade() {
+    let harness = Harness::new();
+    let res = harness.get("/api/external-engine/work/socket").await;
+    assert_eq!(res.status(), StatusCode::UPGRADE_REQUIRED);
+    assert_eq!(read_body(res).await, "websocket upgrade required");
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_analyse_event_stream() {
+    let harness = Harness::new();
+    let analysis = harness.send(
+        Request::post(format!("/api/external-engine/{ENGINE_ID}/analyse"))
+            .header("content-type", "application/json")
+            .header("accept", "text/event-stream")
+            .body(Body::from(
+                json!({ "clientSecret": CLIENT_SECRET, "work": work(1) }).to_string(),
+            ))
+            .unwrap(),
+    );
+
+    let (_, mut provider, submitted) = harness.pick_up().await;
+    let res = analysis.await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(res.headers()["content-type"], "text/event-stream");
+    assert!(res.headers().contains_key("x-job-id"));
+
+    provider
+        .send("info depth 1 score cp 20 nodes 20 time 1 pv e8e7")
+        .await;
+    provider.send("bestmove e8e7").await;
+    assert_eq!(submitted.await.unwrap().status(), StatusCode::OK);
+
+    let body = String::from_utf8(read_body(res).await.to_vec()).unwrap();
+    let events: Vec<Value> = body
+        .lines()
+        .filter_map(|line| line.strip_prefix("data:"))
+        .map(|data| serde_json::from_str(data.trim()).unwrap())
+        .collect();
+    assert_eq!(events.len(), 2);
+    assert_eq!(events[0]["depth"], 1);
+    assert_eq!(events[0]["pvs"][0]["moves"], json!(["e8e7"]));
+    assert_eq!(events[1]["bestmove"], "e8e7");
+}
+
+#[tokio::test]
+async fn test_client_socket() {
+    let harness = Harness::new();
+    let mut client = SocketClient::connect(
+        &harness,
+        &format!("/api/external-engine/{ENGINE_ID}/socket"),
+        json!({ "clientSecret": CLIENT_SECRET }),
+    )
+    .await;
+
+    client.send(json!({ "work": work(1) })).await;
+    let (acquired, mut provider, submitted) = harness.pick_up().await;
+    let first_id = acquired["id"].as_str().unwrap().to_owned();
+    provider
+        .send("info depth 1 score cp 20 nodes 20 time 1 pv e8e7")
+        .await;
+    let emit = client.recv().await;
+    assert_eq!(emit["type"], "emit");
+    assert_eq!(emit["id"], first_id.as_str());
+    assert_eq!(emit["depth"], 1);
+
+    // New work for the same session supersedes the previous work.
+    let mut other = work(1);
+    other["moves"] = json!(["e2e4"]);
+    client.send(json!({ "work": other })).await;
+    let (acquired, mut provider, second_submitted) = harness.pick_up().await;
+    let second_id = acquired["id"].as_str().unwrap().to_owned();
+    assert_ne!(first_id, second_id);
+    assert_eq!(submitted.await.unwrap().status(), StatusCode::GONE);
+
+    provider
+        .send("info depth 3 score cp 30 nodes 20 time 1 pv e7e5")
+        .await;
+    let emit = client.recv().await;
+    assert_eq!(emit["id"], second_id.as_str());
+    assert_eq!(emit["depth"], 3);
+    provider.send("bestmove e7e5").await;
+    assert_eq!(second_submitted.await.unwrap().status(), StatusCode::OK);
+    let emit = client.recv().await;
+    assert_eq!(emit["id"], second_id.as_str());
+    assert_eq!(emit["bestmove"], "e7e5");
+    assert_eq!(
+        client.recv().await,
+        json!({ "type": "done", "id": second_id })
+    );
+
+    // Errors are reported without closing the socket.
+    client.send(json!({ "work": work(9) })).await;
+    assert_eq!(
+        client.recv().await,
+        json!({ "type": "error", "error": "invalid work: multiPv must be at most 5" })
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_extended_info() {
+    let harness = Harness::new();
+    let mut extended = work(1);
+    extended["extendedInfo"] = json!(true);
+    let analysis = harness.analyse(extended);
+    let (acquired, mut provider, submitted) = harness.pick_up().await;
+    assert_eq!(acquired["work"]["extendedInfo"], true);
+    let mut emits = EmitReader::new(analysis.await.unwrap());
+
+    provider
+        .send("info depth 5 currmove e8e7 currmovenumber 3 hashfull 12")
+        .await;
+    provider
+        .send("info depth 5 seldepth 7 score cp 20 nodes 2000 nps 1000 tbhits 3 time 2 pv e8e7")
+        .await;
+    assert_eq!(
+        emits.next().await.unwrap(),
+        json!({
+            "time": 2,
+            "depth": 5,
+            "nodes": 2000,
+            "pvs": [{ "moves": ["e8e7"], "cp": -20, "depth": 5 }],
+            "seldepth": 7,
+            "nps": 1000,
+            "hashfull": 12,
+            "tbhits": 3,
+            "currmove": "e8e7",
+            "currmovenumber": 3,
+        })
+    );
+    provider.send("bestmove e8e7").await;
+    assert_eq!(submitted.await.unwrap().status(), StatusCode::OK);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_extended_info_castling() {
+    let harness = Harness::new();
+    let mut castling = work(1);
+    castling["initialFen"] = json!("r3k2r/8/8/8/8/8/8/R3K2R w KQkq - 0 1");
+    castling["moves"] = json!(["a1b1"]);
+    castling["extendedInfo"] = json!(true);
+    let analysis = harness.analyse(castling);
+    let (_acquired, mut provider, submitted) = harness.pick_up().await;
+    let mut emits = EmitReader::new(analysis.await.unwrap());
+
+    provider.send("info depth 5 currmove e8g8").await;
+    provider
+        .send("info depth 5 score cp 20 nodes 2000 time 2 pv e8g8")
+        .await;
+    let emit = emits.next().await.unwrap();
+    assert_eq!(emit["currmove"], "e8h8");
+    assert_eq!(emit["pvs"][0]["moves"], json!(["e8h8"]));
+    provider.send("bestmove e8g8").await;
+    assert_eq!(submitted.await.unwrap().status(), StatusCode::OK);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_info_strings() {
+    let harness = Harness::new();
+    let mut verbose = work(1);
+    verbose["infoStrings"] = json!(true);
+    let analysis = harness.analyse(verbose);
+    let (acquired, mut provider, submitted) = harness.pick_up().await;
+    assert_eq!(acquired["work"]["infoStrings"], true);
+    let mut emits = EmitReader::new(analysis.await.unwrap());
+
+    provider.send("info string NNUE evaluation enabled").await;
+    provider
+        .send("info depth 1 score cp 20 nodes 20 time 1 pv e8e7")
+        .await;
+    let emit = emits.next().await.unwrap();
+    assert_eq!(emit["strings"], json!(["NNUE evaluation enabled"]));
+
+    // Strings are only emitted once.
+    provider
+        .send("info depth 2 score cp 25 nodes 50 time 2 pv e8e7")
+        .await;
+    let emit = emits.next().await.unwrap();
+    assert_eq!(emit["strings"], json!([]));
+
+    provider.send("bestmove e8e7 ponder e2e1").await;
+    let emit = emits.next().await.unwrap();
+    assert_eq!(emit["depth"], 2);
+    assert_eq!(emit["bestmove"], "e8e7");
+    assert_eq!(emit["ponder"], "e2e1");
+    assert_eq!(emits.next().await, None);
+    assert_eq!(submitted.await.unwrap().status(), StatusCode::OK);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_wdl() {
+    let harness = Harness::new();
+    let analysis = harness.analyse(work(1));
+    let (_, mut provider, submitted) = harness.pick_up().await;
+    let mut emits = EmitReader::new(analysis.await.unwrap());
+
+    provider
+        .send("info depth 1 score cp 20 wdl 300 600 100 nodes 20 time 1 pv e8e7")
+        .await;
+    assert_eq!(
+        emits.next().await.unwrap()["pvs"],
+        json!([{ "moves": ["e8e7"], "cp": -20, "wdl": [100, 600, 300], "depth": 1 }])
+    );
+    provider.send("bestmove e8e7").await;
+    assert_eq!(emits.next().await.unwrap()["bestmove"], "e8e7");
+    assert_eq!(submitted.await.unwrap().status(), StatusCode::OK);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_uci_commands() {
+    let harness = Harness::new();
+    let mut antichess = work(2);
+    antichess["variant"] = json!("antichess");
+    antichess["depth"] = json!(20);
+    antichess.as_object_mut().unwrap().remove("movetime");
+    antichess["initialFen"] = json!("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w - - 0 1");
+    let _analysis = harness.analyse(antichess);
+    let (acquired, _provider, _submitted) = harness.pick_up().await;
+
+    let work: Work = serde_json::from_value(acquired["work"].clone()).unwrap();
+    let commands: Vec<String> = work
+        .to_uci_commands()
+        .iter()
+        .map(ToString::to_string)
+        .collect();
+    assert_eq!(
+        commands,
+        [
+            "setoption name UCI_Chess960 value true",
+            "setoption name UCI_Variant value antichess",
+            "setoption name Threads value 8",
+            "setoption name Hash value 2048",
+            "setoption name MultiPV value 2",
+            "setoption name UCI_AnalyseMode value true",
+            "ucinewgame",
+            "isready",
+            "position fen rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w - - 0 1 moves e2e4 e7e5 e1e2",
+            "go depth 20",
+        ]
+    );
+}
+
+const FAKE_ENGINE: &str = r#"
+while read -r line; do
+    echo "$line" >> "$0.log"
+    case "$line" in
+        uci) echo "id name Fake"; echo "uciok" ;;
+        isready) echo "readyok" ;;
+        go*)
+            echo "info string searching"
+            echo "info depth 1 score cp 20 nodes 20 time 1 pv e8e7"
+            echo "bestmove e8e7"
+            ;;
+    esac
+done
+"#;
+
+#[tokio::test]
+async fn test_reference_provider() {
+    let engine = std::env::temp_dir().join(format!("lila-engine-fake-{}", std::process::id()));
+    std::fs::write(&engine, FAKE_ENGINE).unwrap();
+    let log = engine.with_extension("log");
+    let _ = std::fs::remove_file(&log);
+
+    let harness = Harness::new();
+    let addr = harness.serve();
+    let provider = task::spawn(provider::run(ProviderConfig {
+        endpoint: format!("http://{addr}").parse().unwrap(),
+        provider_secret: serde_json::from_value(json!(PROVIDER_SECRET)).unwrap(),
+        instance: None,
+        engine: "/bin/sh".into(),
+        engine_args: vec![engine.clone().into()],
+        retry_after: Duration::from_secs(1),
+        uci_parsing: Parsing::Strict,
+    }));
+
+    let res = harness.analyse(work(1)).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let mut emits = EmitReader::new(res);
+    assert_eq!(emits.next().await.unwrap()["depth"], 1);
+    assert_eq!(emits.next().await.unwrap()["bestmove"], "e8e7");
+    assert_eq!(emits.next().await, None);
+    provider.abort();
+
+    let commands = std::fs::read_to_string(&log).unwrap();
+    assert_eq!(
+        commands.lines().collect::<Vec<_>>(),
+        [
+            "uci",
+            "setoption name UCI_Chess960 value true",
+            "setoption name UCI_Variant value chess",
+            "setoption name Threads value 8",
+            "setoption name Hash value 2048",
+            "setoption name MultiPV value 1",
+            "setoption name UCI_AnalyseMode value true",
+            "ucinewgame",
+            "isready",
+            "position fen rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1 moves e2e4 e7e5 e1e2",
+            "go movetime 1000",
+        ]
+    );
+    let _ = std::fs::remove_file(&engine);
+    let _ = std::fs::remove_file(&log);
+}
+
+const LENIENT_ENGINE: &str = r#"
+while read -r line; do
+    case "$line" in
+        uci) echo "uciok" ;;
+        isready) echo "readyok" ;;
+        go*)
+            echo "info depth 1 score cp 20 movesleft 42 nodes 20 time 1 pv e8e7"
+            echo "bestmove e8e7"
+            ;;
+    esac
+done
+"#;
+
+#[tokio::test]
+async fn test_reference_provider_lenient() {
+    let engine = std::env::temp_dir().join(format!("lila-engine-lenient-{}", std::process::id()));
+    std::fs::write(&engine, LENIENT_ENGINE).unwrap();
+
+    let harness = Harness::with_limits(Limits {
+        uci_parsing: Parsing::Lenient,
+        ..Limits::default()
+    });
+    let addr = harness.serve();
+    let provider = task::spawn(provider::run(ProviderConfig {
+        endpoint: format!("http://{addr}").parse().unwrap(),
+        provider_secret: serde_json::from_value(json!(PROVIDER_SECRET)).unwrap(),
+        instance: None,
+        engine: "/bin/sh".into(),
+        engine_args: vec![engine.clone().into()],
+        retry_after: Duration::from_secs(1),
+        uci_parsing: Parsing::Lenient,
+    }));
+
+    let res = harness.analyse(work(1)).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let mut emits = EmitReader::new(res);
+    assert_eq!(
+        emits.next().await.unwrap()["pvs"],
+        json!([{ "moves": ["e8e7"], "cp": -20, "depth": 1 }])
+    );
+    assert_eq!(emits.next().await.unwrap()["bestmove"], "e8e7");
+    provider.abort();
+    let _ = std::fs::remove_file(&engine);
+}
+
+#[tokio::test]
+async fn test_simulated_engine() {
+    let harness = Harness::new();
+    let addr = harness.serve();
+    let provider = task::spawn(provider::run(ProviderConfig {
+        endpoint: format!("http://{addr}").parse().unwrap(),
+        provider_secret: serde_json::from_value(json!(PROVIDER_SECRET)).unwrap(),
+        instance: None,
+        engine: env!("CARGO_BIN_EXE_lila-engine-simulator").into(),
+        engine_args: ["--depth", "3", "--interval", "0", "--malformed-every", "3"]
+            .into_iter()
+            .map(Into::into)
+            .collect(),
+        retry_after: Duration::from_secs(1),
+        uci_parsing: Parsing::Strict,
+    }));
+
+    let res = harness.analyse(work(2)).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let mut emits = EmitReader::new(res);
+    let mut depths = Vec::new();
+    let mut last = None;
+    while let Some(emit) = emits.next().await {
+        depths.push(emit["depth"].as_u64().unwrap());
+        last = Some(emit);
+    }
+    provider.abort();
+
+    // Malformed lines were dropped by the provider.
+    assert!(depths.windows(2).all(|w| w[0] <= w[1]));
+    assert_eq!(depths.last(), Some(&3));
+    let last = last.unwrap();
+    assert_eq!(last["pvs"].as_array().unwrap().len(), 2);
+    assert_eq!(last["pvs"][0]["moves"].as_array().unwrap().len(), 3);
+    assert_eq!(last["bestmove"], last["pvs"][0]["moves"][0]);
+    assert_eq!(last["ponder"], last["pvs"][0]["moves"][1]);
+}
+
+#[tokio::test]
+async fn test_simulated_engine_variants() {
+    let harness = Harness::new();
+    let addr = harness.serve();
+    let provider = task::spawn(provider::run(ProviderConfig {
+        endpoint: format!("http://{addr}").parse().unwrap(),
+        provider_secret: serde_json::from_value(json!(PROVIDER_SECRET)).unwrap(),
+        instance: None,
+        engine: env!("CARGO_BIN_EXE_lila-engine-simulator").into(),
+        engine_args: ["--depth", "2", "--interval", "0"]
+            .into_iter()
+            .map(Into::into)
+            .collect(),
+        retry_after: Duration::from_secs(1),
+        uci_parsing: Parsing::Strict,
+    }));
+
+    // The same engine process analyses both, so the variant of the first
+    // must not leak into the second.
+    let mut antichess = work(1);
+    antichess["variant"] = json!("antichess");
+    antichess["initialFen"] = json!("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w - - 0 1");
+    antichess["infoStrings"] = json!(true);
+    let mut chess = work(1);
+    chess["infoStrings"] = json!(true);
+    for work in [antichess, chess] {
+        let res = harness.analyse(work).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let mut emits = EmitReader::new(res);
+        let mut last = None;
+        while let Some(emit) = emits.next().await {
+            assert_eq!(emit["strings"], json!([]));
+            last = Some(emit);
+        }
+        assert!(last.unwrap()["bestmove"].is_string());
+    }
+    provider.abort();
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_searchmoves() {
+    let harness = Harness::new();
+    let mut castling = work(1);
+    castling["initialFen"] = json!("r3k2r/8/8/8/8/8/8/R3K2R w KQkq - 0 1");
+    castling["moves"] = json!(["a1b1"]);
+
+    castling["searchmoves"] = json!(["e1g1"]);
+    let res = harness.analyse(castling.clone()).await.unwrap();
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+    castling["searchmoves"] = json!(["e8g8", "a8a1", "e8h8"]);
+    let _analysis = harness.analyse(castling);
+    let (acquired, _provider, _submitted) = harness.pick_up().await;
+    assert_eq!(acquired["work"]["searchmoves"], json!(["e8h8", "a8a1"]));
+
+    let work: Work = serde_json::from_value(acquired["work"].clone()).unwrap();
+    assert_eq!(
+        work.to_uci_commands().last().unwrap().to_string(),
+        "go searchmoves e8h8 a8a1 movetime 1000"
+    );
+}
+
+fn infinite_work() -> Value {
+    let mut infinite = work(1);
+    infinite.as_object_mut().unwrap().remove("movetime");
+    infinite["infinite"] = json!(true);
+    infinite
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_infinite_time_limit() {
+    let harness = Harness::with_limits(Limits {
+        max_infinite: Duration::from_secs(30),
+        ..Limits::default()
+    });
+
+    let mut invalid = infinite_work();
+    invalid["infinite"] = json!(false);
+    let res = harness.analyse(invalid).await.unwrap();
+    assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+    let analysis = harness.analyse(infinite_work());
+    let (acquired, mut provider, submitted) = harness.pick_up().await;
+    assert_eq!(acquired["work"]["infinite"], true);
+    let mut emits = EmitReader::new(analysis.await.unwrap());
+
+    provider
+        .send("info depth 20 score cp 20 nodes 20 time 1 pv e8e7 e2e1 g8f6")
+        .await;
+    assert_eq!(emits.next().await.unwrap()["depth"], 20);
+
+    // The broker stops the search and completes the final emit.
+    sleep(Duration::from_secs(31)).await;
+    assert_eq!(submitted.await.unwrap().status(), StatusCode::OK);
+    let emit = emits.next().await.unwrap();
+    assert_eq!(emit["depth"], 20);
+    assert_eq!(emit["bestmove"], "e8e7");
+    assert_eq!(emit["ponder"], "e2e1");
+    assert_eq!(emits.next().await, None);
+}
+
+#[tokio::test]
+async fn test_infinite_socket_stop() {
+    let harness = Harness::with_limits(Limits {
+        max_infinite: Duration::from_millis(200),
+        ..Limits::default()
+    });
+    let mut provider = SocketClient::connect(
+        &harness,
+        "/api/external-engine/work/socket",
+        json!({ "providerSecret": PROVIDER_SECRET }),
+    )
+    .await;
+
+    let analysis = harness.analyse(infinite_work());
+    let pushed = provider.recv().await;
+    let id = pushed["id"].as_str().unwrap().to_owned();
+    provider
+        .line(&id, "info depth 1 score cp 20 nodes 20 time 1 pv e8e7")
+        .await;
+    let mut emits = EmitReader::new(analysis.await.unwrap());
+    assert_eq!(emits.next().await.unwrap()["depth"], 1);
+
+    assert_eq!(provider.recv().await, json!({ "type": "stop", "id": id }));
+    assert_eq!(emits.next().await.unwrap()["bestmove"], "e8e7");
+    assert_eq!(emits.next().await, None);
+}